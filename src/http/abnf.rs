@@ -1,5 +1,3 @@
-use regex::Regex;
-
 // ABNF rules: https://datatracker.ietf.org/doc/html/rfc5234#autoid-24
 
 // ALPHA =  %x41-5A / %x61-7A   ; A-Z / a-z
@@ -38,12 +36,13 @@ pub const WSP: &str = r"[[:blank:]]";
 #[cfg(test)]
 mod tests {
     use super::*;
+    use regex::Regex;
 
     #[test]
     fn test_alpha() {
         let re = Regex::new(ALPHA).unwrap();
         let mat = re.find("123a321").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 3);
     }
 
@@ -51,11 +50,11 @@ mod tests {
     fn test_char() {
         let re = Regex::new(CHAR).unwrap();
         let mat = re.find(" ").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 0);
 
         let mat = re.find("~").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 0);
     }
 
@@ -63,7 +62,7 @@ mod tests {
     fn test_crlf() {
         let re = Regex::new(CRLF).unwrap();
         let mat = re.find("aaaa\r\n").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 4);
     }
 
@@ -71,7 +70,7 @@ mod tests {
     fn test_dquote() {
         let re = Regex::new(DQUOTE).unwrap();
         let mat = re.find("dd\"dd").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 2);
     }
 
@@ -79,17 +78,14 @@ mod tests {
     fn test_lwsp() {
         let re = Regex::new(LWS).unwrap();
         let mat = re.find("hello \r\n").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 5);
         assert_eq!(mat.end(), 6);
 
-        match re.find("hello\r\n") {
-            Some(_) => assert!(false),
-            None => assert!(true),
-        }
+        assert!(re.find("hello\r\n").is_none());
 
         let mat = re.find("hello\r\n ").unwrap();
-        assert_eq!(mat.is_empty(), false);
+        assert!(!mat.is_empty());
         assert_eq!(mat.start(), 5);
         assert_eq!(mat.end(), 8)
     }
@@ -0,0 +1,158 @@
+use bytes::Bytes;
+use std::io::{self, ErrorKind, Read};
+use std::sync::{Arc, Mutex};
+
+/// Size of the chunks read from a reader backed body
+const CHUNK_SIZE: usize = 8192;
+
+/// Chunks of a stream body, in the order they are written
+pub type Chunks = Box<dyn Iterator<Item = io::Result<Bytes>> + Send>;
+
+/// Message body, either held in memory or produced chunk by chunk while it is written.
+/// A stream can only be consumed once, clones share the same underlying stream.
+#[derive(Clone)]
+pub enum Body {
+    Full(Bytes),
+    Stream(Arc<Mutex<Option<Chunks>>>),
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Self::Full(Bytes::new())
+    }
+
+    /// Body produced by an iterator of chunks, chunks are written as they are produced
+    pub fn from_chunks<I, C>(chunks: I) -> Self
+    where
+        I: Iterator<Item = C> + Send + 'static,
+        C: Into<Bytes>,
+    {
+        Self::from_stream(chunks.map(|i| Ok(i.into())))
+    }
+
+    /// Body produced by a fallible iterator of chunks, an error aborts the response
+    pub fn from_stream<I>(chunks: I) -> Self
+    where
+        I: Iterator<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::Stream(Arc::new(Mutex::new(Some(Box::new(chunks)))))
+    }
+
+    /// Body read from a reader until end of file
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self::from_stream(ReaderChunks {
+            reader,
+            done: false,
+        })
+    }
+
+    /// Length of the body, `None` for streams where the length is unknown until written
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Self::Full(val) => Some(val.len()),
+            Self::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Bytes of a full body, `None` for streams
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match self {
+            Self::Full(val) => Some(val),
+            Self::Stream(_) => None,
+        }
+    }
+
+    /// Full body decoded as UTF-8 text, `None` for streams and invalid UTF-8
+    pub fn text(&self) -> Option<String> {
+        let bytes = self.as_bytes()?;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Self::Stream(_))
+    }
+
+    /// Take the chunks of a stream body for writing, `None` if already taken or not a stream
+    pub fn take_stream(&self) -> Option<Chunks> {
+        match self {
+            Self::Full(_) => None,
+            Self::Stream(val) => match val.lock() {
+                Ok(mut val) => val.take(),
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(value: Bytes) -> Self {
+        Self::Full(value)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Full(Bytes::from(value))
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(value: &[u8]) -> Self {
+        Self::Full(Bytes::copy_from_slice(value))
+    }
+}
+
+impl From<String> for Body {
+    fn from(value: String) -> Self {
+        Self::Full(Bytes::from(value))
+    }
+}
+
+impl From<&str> for Body {
+    fn from(value: &str) -> Self {
+        Self::Full(Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+struct ReaderChunks<R: Read> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: Read> Iterator for ReaderChunks<R> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Some(Ok(Bytes::from(chunk)));
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+/// Header fields of a message.
+/// Names are matched case-insensitively, a name can have several values, and fields
+/// keep the order they were added in so they are written back out the same way.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self { fields: Vec::new() }
+    }
+
+    /// Get the first value of a header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get every value of a header, in the order they were added
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set a header, replacing any existing values.
+    /// The field keeps the position of the first value it replaces.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        let mut replaced = false;
+        self.fields.retain_mut(|(key, val)| {
+            if !key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if replaced {
+                return false;
+            }
+            *key = String::from(name);
+            *val = String::from(value);
+            replaced = true;
+            true
+        });
+        if !replaced {
+            self.append(name, value);
+        }
+    }
+
+    /// Add a value for a header, existing values are kept
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((String::from(name), String::from(value)));
+    }
+
+    /// Remove every value of a header, returns whether any were removed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.fields.len();
+        self.fields.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        len != self.fields.len()
+    }
+
+    /// Iterate over the fields as name value pairs, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self {
+            fields: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_is_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.append("User-Agent", "curl/7.64.1");
+        assert_eq!(headers.get("user-agent"), Some("curl/7.64.1"));
+        assert_eq!(headers.get("USER-AGENT"), Some("curl/7.64.1"));
+        assert!(headers.contains("User-agent"));
+        assert_eq!(headers.get("Accept"), None);
+    }
+
+    #[test]
+    fn test_append_keeps_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/plain");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(headers.get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(
+            headers.iter().collect::<Vec<(&str, &str)>>(),
+            vec![("Set-Cookie", "a=1"), ("Content-Type", "text/plain"), ("set-cookie", "b=2")]
+        );
+    }
+
+    #[test]
+    fn test_insert_replaces_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Vary", "Accept");
+        headers.append("Host", "localhost");
+        headers.append("vary", "Origin");
+        headers.insert("VARY", "*");
+        assert_eq!(
+            headers.iter().collect::<Vec<(&str, &str)>>(),
+            vec![("VARY", "*"), ("Host", "localhost")]
+        );
+
+        headers.insert("Accept", "*/*");
+        assert_eq!(headers.len(), 3);
+        assert!(headers.remove("accept"));
+        assert!(!headers.remove("accept"));
+        assert_eq!(headers.len(), 2);
+    }
+}
@@ -1,7 +1,11 @@
-use crate::http::abnf::{CRLF, SP, VCHAR};
-use crate::http::types;
+use crate::http::error::HttpError;
+use crate::http::types::{HttpMethod, HttpVersion, RequestLine};
 
-use regex;
+use nom::bytes::streaming::{tag, take_while, take_while1};
+use nom::combinator::{map_res, opt, recognize};
+use nom::sequence::{pair, tuple};
+use nom::IResult;
+use std::str::FromStr;
 
 // Hypertext Transfer Protocol -- HTTP/1.1 -- https://datatracker.ietf.org/doc/html/rfc2068
 
@@ -11,190 +15,521 @@ use regex;
 //      Headers:         Host: localhost:4221\r\n
 //      Entity:          (empty)\r\n
 
-// Text = <any OCTET except CTLs, but including LWS>
-// const TEXT: &str = r"[]";
-// HEX = "A" | "B" | "C" | "D" | "E" | "F" | "a" | "b" | "c" | "d" | "e" | "f" | DIGIT
-const HEX: &str = r"[[:xdigit:]]";
+// HTTP-message   = start-line CRLF
+//                  *( field-line CRLF )
+//                  CRLF
+//                  [ message-body ]
 
-// token          = 1*tchar
-// tchar          = "!" / "#" / "$" / "%" / "&" / "'" / "*"
-//                 / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~"
-//                 / DIGIT / ALPHA
-// const TOKEN: &str = r"[\x30-\x39\x41-\x5A\x61-\x7A]";
-const TOKEN: &str = r"[!#$%&'*+-.^_`|~[[:alnum:]]]";
+// The parsers below are nom streaming parsers, running out of input part way through
+// a message is reported as incomplete rather than as an error, so the same buffer can
+// be parsed again once more bytes have arrived.
 
-// tspecials = "(" | ")" | "<" | ">" | "@" | "," | ";" | ":" | "\" | <"> | "/" | "[" | "]" | "?" | "=" | "{" | "}" | SP  |  HT
+/// Request line and header fields of a request, borrowed from the buffer they were parsed from
+#[derive(Debug, PartialEq)]
+pub struct RequestHead<'a> {
+    pub method: &'a str,
+    pub target: &'a str,
+    pub version: &'a str,
+    /// Header fields in the order they were sent, values may contain obs-text
+    pub headers: Vec<(&'a str, &'a [u8])>,
+    /// Position of the first byte after the header block, where the body starts
+    pub body_offset: usize,
+}
 
-const TSPECIALS: &str =
-    r"\x28\x29\x3C\x3E\x40\x2C\x3B\x3A\x5C\x22\x2F\x5B\x5D\x3F\x3D\x7B\x7D\x20\x09";
+impl RequestHead<'_> {
+    /// Request line with the method and version checked
+    pub fn request_line(&self) -> Result<RequestLine, HttpError> {
+        Ok(RequestLine {
+            method: HttpMethod::from_str(self.method)?,
+            target: String::from(self.target),
+            version: HttpVersion::from_str(self.version)?,
+        })
+    }
 
-    // HTTP-Version   = "HTTP" "/" 1*DIGIT "." 1*DIGIT"GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n") {
-        //     Some(val) => {
-        //         println!("{:?}", val);
-        //     },
-        //     None => {},
-        // }
-const HTTP_VERSION: &str = r"HTTP\/[0-9\.]{1,3}";
+    /// Owned copies of the header fields, values are decoded one octet per char
+    pub fn fields(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (String::from(*name), decode_latin1(value)))
+            .collect()
+    }
+}
 
+/// Outcome of parsing the bytes received so far
+#[derive(Debug, PartialEq)]
+pub enum ParseStatus<T> {
+    Complete(T),
+    /// The message ends part way through, parse again once more bytes have arrived
+    Partial,
+    /// The message is malformed at `position`
+    Error(usize),
+}
+
+// Parse a request line and header block from the start of `data`
+pub fn parse_request_head(data: &[u8]) -> ParseStatus<RequestHead<'_>> {
+    match request_head(data) {
+        Ok((rest, (method, target, version, headers))) => ParseStatus::Complete(RequestHead {
+            method,
+            target,
+            version,
+            headers,
+            body_offset: data.len() - rest.len(),
+        }),
+        Err(nom::Err::Incomplete(_)) => ParseStatus::Partial,
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => ParseStatus::Error(data.len() - e.input.len()),
+    }
+}
 
-// Parse header values from the request
-pub fn parse_headers(request: &str) -> Vec<(String, String)> {
-    // HTTP-message   = start-line CRLF
-    //                *( field-line CRLF )
-    //                CRLF
-    //                [ message-body ]
+type Fields<'a> = Vec<(&'a str, &'a [u8])>;
 
-    // field-line   = field-name ":" OWS field-value OWS
+fn request_head(input: &[u8]) -> IResult<&[u8], (&str, &str, &str, Fields<'_>)> {
+    let (mut input, (method, target, version)) = request_line(input)?;
 
+    let mut headers = Vec::new();
+    loop {
+        // An empty line ends the header block
+        match tag::<_, _, nom::error::Error<&[u8]>>("\r\n")(input) {
+            Ok((rest, _)) => return Ok((rest, (method, target, version, headers))),
+            Err(nom::Err::Incomplete(needed)) => return Err(nom::Err::Incomplete(needed)),
+            Err(_) => {}
+        }
+        let (rest, field) = field_line(input)?;
+        headers.push(field);
+        input = rest;
+    }
+}
+
+fn request_line(input: &[u8]) -> IResult<&[u8], (&str, &str, &str)> {
+    // request-line   = method SP request-target SP HTTP-version CRLF
+    // method         = token
+    // HTTP-version   = HTTP-name "/" DIGIT "." DIGIT
+    let (input, method) = map_res(take_while1(is_tchar), std::str::from_utf8)(input)?;
+    let (input, _) = tag(" ")(input)?;
+    let (input, target) = map_res(take_while1(is_vchar), std::str::from_utf8)(input)?;
+    let (input, _) = tag(" ")(input)?;
+    let (input, version) = map_res(
+        recognize(tuple((
+            tag("HTTP/"),
+            take_while1(is_digit),
+            opt(pair(tag("."), take_while1(is_digit))),
+        ))),
+        std::str::from_utf8,
+    )(input)?;
+    let (input, _) = tag("\r\n")(input)?;
+    Ok((input, (method, target, version)))
+}
+
+fn field_line(input: &[u8]) -> IResult<&[u8], (&str, &[u8])> {
+    // field-line     = field-name ":" OWS field-value OWS
     // field-name     = token
     // field-value    = *field-content
     // field-content  = field-vchar
     //                  [ 1*( SP / HTAB / field-vchar ) field-vchar ]
     // field-vchar    = VCHAR / obs-text
     // obs-text       = %x80-FF
-    // let re = regex::Regex::new(&format!(r"(?P<name>({TOKEN}+):{SP}+(?P<value>({VCHAR}|[\x80-\xFF])+)\r\n)")).unwrap();
-    let re = regex::Regex::new(&format!(r"(?P<name>{TOKEN}+):{SP}+(?P<value>({VCHAR}|[\x80-\xFF])+)\r\n")).unwrap();
-
-    let mut headers = Vec::<(String, String)>::new();
-
-    for i in re.find_iter(request) {
-        match re.captures(i.as_str()) {
-            Some(val) => {
-                let name = match val.name("name") {
-                    Some(m) => m.as_str(),
-                    None => "",
-                };
-                let value = match val.name("value") {
-                    Some(m) => m.as_str(),
-                    None => "",
-                };
-                if name != "" && value != "" {
-                    headers.push((String::from(name), String::from(value)));
+    // obs-fold lines start with whitespace, they fail the field-name and are rejected
+    let (input, name) = map_res(take_while1(is_tchar), std::str::from_utf8)(input)?;
+    let (input, _) = tag(":")(input)?;
+    let (input, _) = take_while(is_ows)(input)?;
+    let (input, value) = take_while(|i| is_ows(i) || is_vchar(i) || i >= 0x80)(input)?;
+    let (input, _) = tag("\r\n")(input)?;
+
+    let end = value.iter().rposition(|i| !is_ows(*i)).map_or(0, |i| i + 1);
+    Ok((input, (name, &value[..end])))
+}
+
+// tchar          = "!" / "#" / "$" / "%" / "&" / "'" / "*"
+//                 / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~"
+//                 / DIGIT / ALPHA
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+// VCHAR = %x21-7E
+fn is_vchar(c: u8) -> bool {
+    (0x21..=0x7E).contains(&c)
+}
+
+fn is_digit(c: u8) -> bool {
+    c.is_ascii_digit()
+}
+
+// OWS = *( SP / HTAB )
+fn is_ows(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+// Decode message bytes one octet per char, header fields may contain obs-text (%x80-FF)
+pub fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|i| char::from(*i)).collect()
+}
+
+/// Result of decoding a chunked message body
+#[derive(Debug, PartialEq)]
+pub enum Chunked {
+    /// The whole body has been received, `length` is the number of encoded bytes consumed
+    Complete {
+        body: Vec<u8>,
+        trailers: Vec<(String, String)>,
+        length: usize,
+    },
+    /// The encoded body ends part way through, more data is needed
+    Incomplete,
+    /// The encoding is malformed
+    Invalid,
+    /// A chunk would take the body past the decoder's limit
+    TooLarge,
+}
+
+// Check the Transfer-Encoding headers of a request and return whether the body is chunked
+pub fn is_chunked(headers: &[(String, String)]) -> Result<bool, HttpError> {
+    // Transfer-Encoding = #transfer-coding
+    let codings: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|i| i.trim().to_lowercase())
+        .filter(|i| !i.is_empty())
+        .collect();
+
+    if codings.is_empty() {
+        return Ok(false);
+    }
+    // chunked must be applied last and only once, otherwise the message length can't be determined
+    if codings.last().map(String::as_str) != Some("chunked")
+        || codings.iter().filter(|i| *i == "chunked").count() != 1
+    {
+        return Err(HttpError {
+            code: 400,
+            detail: String::from("Bad Request"),
+        });
+    }
+    if codings.len() > 1 {
+        return Err(HttpError {
+            code: 501,
+            detail: String::from("Not Implemented"),
+        });
+    }
+    Ok(true)
+}
+
+// Decode a chunked message body
+pub fn decode_chunked(data: &[u8]) -> Chunked {
+    ChunkedDecoder::default().decode(data)
+}
+
+/// Where a `ChunkedDecoder` is in the encoded body
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ChunkState {
+    /// Waiting for a chunk-size line
+    #[default]
+    Size,
+    /// Inside chunk-data, which ends at this position
+    Data(usize),
+    /// Past the last chunk, reading trailer fields
+    Trailers,
+}
+
+/// Decodes a chunked message body as it arrives.
+/// Each call to `decode` is given the whole encoded body received so far,
+/// but only looks at the bytes after the position the last call stopped at.
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    /// Encoded bytes consumed so far
+    position: usize,
+    /// Bytes already searched for the end of the current line
+    scanned: usize,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+    /// Longest body accepted, `None` for no limit
+    limit: Option<usize>,
+}
+
+impl ChunkedDecoder {
+    /// Decoder rejecting a chunk as soon as its size line would take the body past `limit`
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    /// Number of body bytes decoded so far
+    pub fn decoded(&self) -> usize {
+        self.body.len()
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Chunked {
+        // chunked-body   = *chunk
+        //                  last-chunk
+        //                  trailer-section
+        //                  CRLF
+        // chunk          = chunk-size [ chunk-ext ] CRLF
+        //                  chunk-data CRLF
+        // chunk-size     = 1*HEXDIG
+        // last-chunk     = 1*("0") [ chunk-ext ] CRLF
+        // chunk-data     = 1*OCTET ; a sequence of chunk-size octets
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let line = match self.next_line(data) {
+                        Some(val) => val,
+                        None => return Chunked::Incomplete,
+                    };
+                    let size = match parse_chunk_size(&data[self.position..line]) {
+                        Some(val) => val,
+                        None => return Chunked::Invalid,
+                    };
+                    // Checked before any of the chunk is read, a huge size can't be waited for
+                    if self.limit.is_some_and(|limit| size > limit.saturating_sub(self.body.len())) {
+                        return Chunked::TooLarge;
+                    }
+                    self.position = line + 2;
+                    self.state = match size {
+                        0 => ChunkState::Trailers,
+                        _ => match self.position.checked_add(size) {
+                            Some(val) => ChunkState::Data(val),
+                            None => return Chunked::Invalid,
+                        },
+                    };
+                }
+                ChunkState::Data(chunk_end) => {
+                    // Take whatever part of the chunk has arrived, so it isn't looked at again
+                    let available = data.len().min(chunk_end);
+                    if available > self.position {
+                        self.body.extend_from_slice(&data[self.position..available]);
+                        self.position = available;
+                    }
+                    let data_end = match chunk_end.checked_add(2) {
+                        Some(val) => val,
+                        None => return Chunked::Invalid,
+                    };
+                    if data.len() < data_end {
+                        return Chunked::Incomplete;
+                    }
+                    if &data[chunk_end..data_end] != b"\r\n" {
+                        return Chunked::Invalid;
+                    }
+                    self.position = data_end;
+                    self.state = ChunkState::Size;
+                }
+                // trailer-section = *( field-line CRLF )
+                ChunkState::Trailers => {
+                    let line = match self.next_line(data) {
+                        Some(val) => val,
+                        None => return Chunked::Incomplete,
+                    };
+                    if line == self.position {
+                        return Chunked::Complete {
+                            body: std::mem::take(&mut self.body),
+                            trailers: std::mem::take(&mut self.trailers),
+                            length: line + 2,
+                        };
+                    }
+                    let field = decode_latin1(&data[self.position..line]);
+                    match field.split_once(':') {
+                        Some((name, value)) if !name.is_empty() && !name.ends_with([' ', '\t']) => {
+                            self.trailers.push((String::from(name), String::from(value.trim())));
+                        }
+                        _ => return Chunked::Invalid,
+                    }
+                    self.position = line + 2;
                 }
-            },
-            None => {},
+            }
         }
     }
-    println!("headers: {:?} {}", &headers, request);
-    headers
-}
-
-// Scan incoming request for the request line and extract details
-pub fn parse_request_line(request: &str) -> Option<(String, String, String)> {
-    // Request-Line = Method SP Request-URI SP HTTP-Version CRLF
-    let request_line_rex = regex::Regex::new(&format!(
-        r"(?P<method>{TOKEN}+){SP}(?P<uri>.*){SP}(?P<version>{HTTP_VERSION}){CRLF}"
-    ))
-    .unwrap();
-
-    match request_line_rex.captures(request) {
-        Some(c) => Some((
-            String::from(&c["method"]),
-            String::from(&c["uri"]),
-            String::from(&c["version"]),
-        )),
-        None => None,
-    }
-}
-
-pub fn parse_body(request: &str) -> Option<String> {
-    let body_regex = regex::Regex::new(&format!(r"({CRLF}{CRLF})(?P<body>.*$)")).unwrap();
-    match body_regex.captures(request) {
-        Some(val) => {
-            match val.name("body") {
-                Some(body) => {
-                    return Some(String::from(body.as_str()))
-                },
-                None => return None,
+
+    // Index of the CRLF ending the line at the current position, resuming the search where the last call gave up
+    fn next_line(&mut self, data: &[u8]) -> Option<usize> {
+        // A CR at the end of the last search may be followed by the LF now
+        let start = self.position.max(self.scanned.saturating_sub(1));
+        match next_line(data, start) {
+            Some(val) => Some(val),
+            None => {
+                self.scanned = data.len();
+                None
             }
-        },
-        None => None,
+        }
     }
 }
 
+// Index of the next CRLF at or after `start`
+fn next_line(data: &[u8], start: usize) -> Option<usize> {
+    data.get(start..)?
+        .windows(2)
+        .position(|i| i == b"\r\n")
+        .map(|i| i + start)
+}
+
+// Parse the chunk-size, ignoring any chunk extensions
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    // chunk-ext      = *( BWS ";" BWS chunk-ext-name
+    //                     [ BWS "=" BWS chunk-ext-val ] )
+    let digits = line.iter().take_while(|i| i.is_ascii_hexdigit()).count();
+    // more than 16 hex digits would overflow
+    if digits == 0 || digits > 16 {
+        return None;
+    }
+
+    let extension = &line[digits..];
+    let extension_start = extension.iter().position(|i| *i != b' ' && *i != b'\t');
+    if let Some(start) = extension_start {
+        if extension[start] != b';' {
+            return None;
+        }
+    }
+
+    let size = std::str::from_utf8(&line[..digits]).ok()?;
+    usize::from_str_radix(size, 16).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_body() {
-        let result = parse_body("GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n12345");
+    fn test_parse_request_head() {
+        let request = b"GET /echo/abc?x=1 HTTP/1.1\r\nHost: localhost:4221\r\nX-Empty:\r\nX-Pad: \t a b \t\r\nX-Latin: caf\xe9\r\n\r\nbody";
+        assert_eq!(
+            parse_request_head(request),
+            ParseStatus::Complete(RequestHead {
+                method: "GET",
+                target: "/echo/abc?x=1",
+                version: "HTTP/1.1",
+                headers: vec![
+                    ("Host", &b"localhost:4221"[..]),
+                    ("X-Empty", &b""[..]),
+                    ("X-Pad", &b"a b"[..]),
+                    ("X-Latin", &b"caf\xe9"[..]),
+                ],
+                body_offset: request.len() - 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_line() {
+        let request_line = |data: &[u8]| match parse_request_head(data) {
+            ParseStatus::Complete(head) => head.request_line(),
+            _ => panic!("incomplete request head"),
+        };
         assert_eq!(
-            result, Some(String::from("12345"))
+            request_line(b"POST /api/v1/task HTTP/2.0\r\n\r\n").ok(),
+            Some(RequestLine {
+                method: HttpMethod::Post,
+                target: String::from("/api/v1/task"),
+                version: HttpVersion::V2,
+            })
         );
+        assert_eq!(request_line(b"GET / HTTP/7.0\r\n\r\n").err().map(|e| e.code), Some(505));
     }
 
     #[test]
-    fn test_parse_headers() {
-
-        // let re = regex::Regex::new(&format!(r"(?P<name>{TOKEN}+):{SP}+(?P<value>({VCHAR}|[\x80-\xFF])+)\r\n")).unwrap();
-
-        // match re.captures("User-Agent: curl/7.64.1\r\n") {
-        //     Some(val) => {
-        //         println!("{} {}", &val["name"], &val["value"]);
-        //     },
-        //     None => {},
-        // }
-        
-        // for i in re.find_iter("GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n") {
-        //     println!("{:?}", i);
-        //     match re.captures(i.as_str()) {
-        //         Some(val) => {
-        //             let name = match val.name("name") {
-        //                 Some(m) => m.as_str(),
-        //                 None => "",
-        //             };
-        //             let value = match val.name("value") {
-        //                 Some(m) => m.as_str(),
-        //                 None => "",
-        //             };
-        //             if name != "" && value != "" {
-        //                 println!("{}: {}", name, value);
-        //             }
-        //         },
-        //         None => {},
-        //     }
-        // }
-
-        // match re.find("GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n") {
-        //     Some(val) => {
-        //         println!("{:?}", val);
-        //     },
-        //     None => {},
-        // }
-
-        let result = parse_headers("GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n");
-        println!("{:?}", result);
-        let desired = vec![
-            (String::from("Host"), String::from("localhost:4221")),
-            (String::from("User-Agent"), String::from("curl/7.64.1")),
-            (String::from("Accept"), String::from("*/*")),
-        ];
-        assert_eq!(result, desired);
-    }    
+    fn test_parse_request_head_partial() {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        // Every prefix of a valid head needs more data
+        for end in 0..request.len() {
+            assert_eq!(parse_request_head(&request[..end]), ParseStatus::Partial);
+        }
+        assert!(matches!(parse_request_head(request), ParseStatus::Complete(_)));
+    }
 
     #[test]
-    fn test_parse_request_line() {
-        let rl = "Get /index.html HTTP/1.1\r\n";
-        let result = parse_request_line(rl);
+    fn test_parse_request_head_error() {
+        assert_eq!(parse_request_head(b"GET  / HTTP/1.1\r\n\r\n"), ParseStatus::Error(4));
+        assert_eq!(parse_request_head(b"GET / FTP/1.1\r\n\r\n"), ParseStatus::Error(6));
+        assert_eq!(parse_request_head(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"), ParseStatus::Error(20));
+        // obs-fold is rejected
+        assert_eq!(parse_request_head(b"GET / HTTP/1.1\r\nA: b\r\n c\r\n\r\n"), ParseStatus::Error(22));
+        assert_eq!(parse_request_head(b"GET / HTTP/1.1\nHost: a\r\n\r\n"), ParseStatus::Error(14));
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        let result = decode_chunked(b"4\r\nWiki\r\n7;name=value\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\n\r\nnext");
+        assert_eq!(
+            result,
+            Chunked::Complete {
+                body: b"Wikipedia in \r\nchunks.".to_vec(),
+                trailers: vec![],
+                length: 53,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_chunked_trailers() {
+        let result = decode_chunked(b"3\r\nabc\r\n0 ; last\r\nChecksum: 1234\r\nExpires: never\r\n\r\n");
         assert_eq!(
-            result.unwrap(),
-            (
-                String::from("Get"),
-                String::from("/index.html"),
-                String::from("HTTP/1.1")
-            )
+            result,
+            Chunked::Complete {
+                body: b"abc".to_vec(),
+                trailers: vec![
+                    (String::from("Checksum"), String::from("1234")),
+                    (String::from("Expires"), String::from("never")),
+                ],
+                length: 52,
+            }
         );
+    }
+
+    #[test]
+    fn test_decode_chunked_incomplete() {
+        assert_eq!(decode_chunked(b""), Chunked::Incomplete);
+        assert_eq!(decode_chunked(b"4\r\nWi"), Chunked::Incomplete);
+        assert_eq!(decode_chunked(b"4\r\nWiki\r\n0\r\n"), Chunked::Incomplete);
+    }
 
-        let rl = "Post /api/v1/task HTTP/2.4\r\n";
-        let result = parse_request_line(rl);
+    #[test]
+    fn test_decode_chunked_in_pieces() {
+        let data = b"4\r\nWiki\r\n7;name=value\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\nChecksum: 1\r\n\r\n";
+        // Fed one more byte each time, as a slow client would send it
+        let mut decoder = ChunkedDecoder::default();
+        for i in 0..data.len() {
+            assert_eq!(decoder.decode(&data[..i]), Chunked::Incomplete);
+        }
+        assert_eq!(decoder.decoded(), 22);
         assert_eq!(
-            result.unwrap(),
-            (
-                String::from("Post"),
-                String::from("/api/v1/task"),
-                String::from("HTTP/2.4")
-            )
+            decoder.decode(data),
+            Chunked::Complete {
+                body: b"Wikipedia in \r\nchunks.".to_vec(),
+                trailers: vec![(String::from("Checksum"), String::from("1"))],
+                length: data.len(),
+            }
         );
     }
+
+    #[test]
+    fn test_decode_chunked_invalid() {
+        assert_eq!(decode_chunked(b"x\r\nWiki\r\n0\r\n\r\n"), Chunked::Invalid);
+        assert_eq!(decode_chunked(b"-4\r\nWiki\r\n0\r\n\r\n"), Chunked::Invalid);
+        assert_eq!(decode_chunked(b"4x\r\nWiki\r\n0\r\n\r\n"), Chunked::Invalid);
+        assert_eq!(decode_chunked(b"2\r\nWiki\r\n0\r\n\r\n"), Chunked::Invalid);
+        assert_eq!(decode_chunked(b"fffffffffffffffff\r\n"), Chunked::Invalid);
+        // The chunk would end at usize::MAX, leaving no room for its CRLF
+        assert_eq!(decode_chunked(b"ffffffffffffffed\r\nWiki\r\n"), Chunked::Invalid);
+        assert_eq!(decode_chunked(b"0\r\nnot a field\r\n\r\n"), Chunked::Invalid);
+    }
+
+    #[test]
+    fn test_decode_chunked_limit() {
+        let data = b"4\r\nWiki\r\n7\r\npedia i\r\n0\r\n\r\n";
+        assert!(matches!(ChunkedDecoder::with_limit(11).decode(data), Chunked::Complete { .. }));
+        // The second chunk is rejected from its size line, before its data arrives
+        assert_eq!(ChunkedDecoder::with_limit(10).decode(&data[..9]), Chunked::Incomplete);
+        assert_eq!(ChunkedDecoder::with_limit(10).decode(&data[..12]), Chunked::TooLarge);
+        assert_eq!(ChunkedDecoder::with_limit(10).decode(b"ffffffffffffffed\r\n"), Chunked::TooLarge);
+    }
+
+    #[test]
+    fn test_is_chunked() {
+        let header = |value: &str| vec![(String::from("Transfer-Encoding"), String::from(value))];
+        assert_eq!(is_chunked(&[]).ok(), Some(false));
+        assert_eq!(is_chunked(&header("chunked")).ok(), Some(true));
+        assert_eq!(is_chunked(&header("Chunked")).ok(), Some(true));
+        assert_eq!(is_chunked(&header("chunked, gzip")).err().map(|e| e.code), Some(400));
+        assert_eq!(is_chunked(&header("chunked, chunked")).err().map(|e| e.code), Some(400));
+        assert_eq!(is_chunked(&header("gzip, chunked")).err().map(|e| e.code), Some(501));
+    }
 }
@@ -1,5 +1,7 @@
 pub mod abnf;
+pub mod body;
 pub mod error;
+pub mod headers;
 pub mod http11;
 pub mod types;
 pub mod uri;
@@ -1,17 +1,65 @@
 use crate::http::error;
 
-#[derive(Clone, PartialEq, Eq)]
+use std::fmt;
+use std::str::FromStr;
+
+// Request methods - https://www.rfc-editor.org/rfc/rfc9110#section-9
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HttpMethod {
     Unset,
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    /// Any other method token, kept as sent by the client
+    Extension(String),
 }
 
 impl HttpMethod {
-    pub fn from_str(s: &str) -> Result<Self, error::HttpError> {
-        match s.to_uppercase().as_str() {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Unset => "",
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Connect => "CONNECT",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Patch => "PATCH",
+            Self::Extension(val) => val,
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for HttpMethod {
+    type Err = error::HttpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Methods are case-sensitive, "Get" is an extension method rather than GET
+        match s {
             "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
             "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "CONNECT" => Ok(Self::Connect),
+            "OPTIONS" => Ok(Self::Options),
+            "TRACE" => Ok(Self::Trace),
+            "PATCH" => Ok(Self::Patch),
+            _ if is_token(s) => Ok(Self::Extension(String::from(s))),
             _ => Err(error::HttpError {
                 code: 400,
                 detail: String::from("Unsupported HTTP Method"),
@@ -20,30 +68,164 @@ impl HttpMethod {
     }
 }
 
-enum HttpVersion {
+// token = 1*tchar
+// tchar = "!" / "#" / "$" / "%" / "&" / "'" / "*" / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~" / DIGIT / ALPHA
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|i| i.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(i))
+}
+
+/// Standard reason phrase for a status code, empty for unknown codes
+pub fn reason_phrase(status_code: usize) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpVersion {
     V1_0,
     V1_1,
     V2,
     V3,
 }
 
-impl HttpVersion {
-    fn from_str(s: &str) -> Result<Self, error::HttpError> {
-        match s {
-            "HTTP/1.0" => Ok(Self::V1_0),
-            "HTTP/1.1" => Ok(Self::V1_1),
-            "HTTP/2" => Ok(Self::V2),
-            "HTTP/3" => Ok(Self::V3),
-            _ => Err(error::HttpError {
+impl FromStr for HttpVersion {
+    type Err = error::HttpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // HTTP-version  = HTTP-name "/" DIGIT "." DIGIT
+        let version = match s.strip_prefix("HTTP/") {
+            Some(val) => val,
+            None => {
+                return Err(error::HttpError {
+                    code: 400,
+                    detail: String::from("Unsupported HTTP Version"),
+                })
+            }
+        };
+        let (major, minor) = version.split_once('.').unwrap_or((version, ""));
+        let is_digit = |i: &str| i.len() == 1 && i.chars().all(|c| c.is_ascii_digit());
+        if !is_digit(major) || !is_digit(minor) {
+            return Err(error::HttpError {
                 code: 400,
                 detail: String::from("Unsupported HTTP Version"),
+            });
+        }
+        // Later minor versions are compatible with the highest one we know
+        match (major, minor) {
+            ("1", "0") => Ok(Self::V1_0),
+            ("1", _) => Ok(Self::V1_1),
+            ("2", _) => Ok(Self::V2),
+            ("3", _) => Ok(Self::V3),
+            _ => Err(error::HttpError {
+                code: 505,
+                detail: String::from("HTTP Version Not Supported"),
             }),
         }
     }
 }
 
-struct RequestLine {
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1_0 => "HTTP/1.0",
+            Self::V1_1 => "HTTP/1.1",
+            Self::V2 => "HTTP/2",
+            Self::V3 => "HTTP/3",
+        }
+    }
+
+    /// Whether a connection using this version stays open between requests
+    /// when the client does not send a `Connection` header
+    pub fn is_persistent_by_default(&self) -> bool {
+        !matches!(self, Self::V1_0)
+    }
+
+    /// Whether messages of this version can use chunked transfer coding
+    pub fn supports_chunked(&self) -> bool {
+        !matches!(self, Self::V1_0)
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// request-line = method SP request-target SP HTTP-version
+#[derive(Debug, PartialEq)]
+pub struct RequestLine {
     pub method: HttpMethod,
-    pub path: String,
+    pub target: String,
     pub version: HttpVersion,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_from_str() {
+        assert_eq!(HttpMethod::from_str("GET").ok(), Some(HttpMethod::Get));
+        assert_eq!(HttpMethod::from_str("DELETE").ok(), Some(HttpMethod::Delete));
+        assert_eq!(
+            HttpMethod::from_str("Delete").ok(),
+            Some(HttpMethod::Extension(String::from("Delete")))
+        );
+        assert_eq!(HttpMethod::from_str("PATCH").ok(), Some(HttpMethod::Patch));
+        assert_eq!(
+            HttpMethod::from_str("PROPFIND").ok(),
+            Some(HttpMethod::Extension(String::from("PROPFIND")))
+        );
+        assert!(HttpMethod::from_str("GET /").is_err());
+        assert!(HttpMethod::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_version_from_str() {
+        assert_eq!(HttpVersion::from_str("HTTP/1.0").ok(), Some(HttpVersion::V1_0));
+        assert_eq!(HttpVersion::from_str("HTTP/1.1").ok(), Some(HttpVersion::V1_1));
+        assert_eq!(HttpVersion::from_str("HTTP/1.2").ok(), Some(HttpVersion::V1_1));
+        assert_eq!(HttpVersion::from_str("HTTP/2.0").ok(), Some(HttpVersion::V2));
+        assert_eq!(HttpVersion::from_str("HTTP/4.0").err().map(|e| e.code), Some(505));
+        assert_eq!(HttpVersion::from_str("HTTP/1.x").err().map(|e| e.code), Some(400));
+        assert_eq!(HttpVersion::from_str("HTTP/1").err().map(|e| e.code), Some(400));
+        assert_eq!(HttpVersion::from_str("HTTP/2").err().map(|e| e.code), Some(400));
+        assert_eq!(HttpVersion::from_str("FTP/1.0").err().map(|e| e.code), Some(400));
+    }
+}
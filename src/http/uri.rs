@@ -1,5 +1,3 @@
-use regex::Regex;
-
 // URI rfc3986 //
 
 // scheme = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
@@ -94,3 +92,94 @@ pub const SUB_DELIMS: &str = r"\!\$\&\'\(\)\*\+\,\;\=";
 //               / path-empty
 
 // absolute-URI  = scheme ":" hier-part [ "?" query ]
+
+// Split a request target into its path and query, any fragment is dropped
+pub fn split_target(target: &str) -> (&str, Option<&str>) {
+    let target = match target.split_once('#') {
+        Some((val, _)) => val,
+        None => target,
+    };
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+// Decode pct-encoded octets, invalid escapes are kept as they are.
+// Form encoded queries also use "+" for a space.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && is_hex_pair(&bytes[i + 1..i + 3]) => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            val => {
+                decoded.push(val);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn is_hex_pair(bytes: &[u8]) -> bool {
+    bytes.len() == 2 && bytes.iter().all(|i| i.is_ascii_hexdigit())
+}
+
+// Parse a query into decoded name value pairs, in order and keeping repeated names.
+// A name without "=" has an empty value.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|i| !i.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_target() {
+        assert_eq!(split_target("/search"), ("/search", None));
+        assert_eq!(split_target("/search?q=x"), ("/search", Some("q=x")));
+        assert_eq!(split_target("/search?q=x?y#top"), ("/search", Some("q=x?y")));
+        assert_eq!(split_target("/search?"), ("/search", Some("")));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c", true), "a b c");
+        assert_eq!(percent_decode("a%20b+c", false), "a b+c");
+        assert_eq!(percent_decode("caf%C3%A9", false), "café");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query("q=rust+lang&tag=a&tag=b&&flag&page=%32"),
+            vec![
+                (String::from("q"), String::from("rust lang")),
+                (String::from("tag"), String::from("a")),
+                (String::from("tag"), String::from("b")),
+                (String::from("flag"), String::from("")),
+                (String::from("page"), String::from("2")),
+            ]
+        );
+    }
+}
@@ -1,24 +1,18 @@
-// use http::types::HttpMethod;
-// use server::{routing,context};
-
-mod http;
-mod server;
-
-use http::types::HttpMethod;
-use server::error::StdServerError;
-use server::application;
-use server::context::{HttpRequest, HttpResponse, RequestContext};
-use server::error::ServerError;
-use server::routing;
-use server::traits::{Request, Response};
-
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use http_server_starter_rust::http::types::HttpMethod;
+use http_server_starter_rust::server::application;
+#[cfg(unix)]
+use http_server_starter_rust::server::listener;
+use http_server_starter_rust::server::listener::Listen;
+use http_server_starter_rust::server::context::{HttpRequest, HttpResponse, RequestContext};
+use http_server_starter_rust::server::error::{ServerError, StdServerError};
+use http_server_starter_rust::server::routing;
+use http_server_starter_rust::server::tls::TlsConfig;
+use http_server_starter_rust::server::traits::{Error, Request, RequestMiddleware, Response};
+
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, OwnedFd};
 use std::path;
-use std::fs;
-
-use env_logger;
-use log;
 
 fn main() {
     env_logger::init();
@@ -32,6 +26,12 @@ fn main() {
     let cfg = application::ServerConfig {
         address: String::from("127.0.0.1"),
         port: 4221,
+        listeners: get_listeners(),
+        tls: match (get_arg("--tls-cert"), get_arg("--tls-key")) {
+            (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key)),
+            _ => None,
+        },
+        ..Default::default()
     };
 
     let router = routing::Router::<HttpRequest, HttpResponse>::new(vec![
@@ -59,14 +59,40 @@ fn main() {
             String::from("/files/{filename}"),
             Box::new(files_post_route),
             vec![HttpMethod::Post],
-        ),
+        )
+        .with_max_body_size(100 * 1024 * 1024),
     ]);
 
-    let app = application::Application::new(cfg, router);
+    let app = application::Application::new(cfg, router).with_middleware(RequestLogger);
+    app.shutdown_handle().shutdown_on_signals();
 
     application::serve(app);
 }
 
+/// Log each request with the status it was answered with
+struct RequestLogger;
+
+impl RequestMiddleware<HttpRequest, HttpResponse> for RequestLogger {
+    fn on_response(
+        &self,
+        ctx: RequestContext<HttpRequest, HttpResponse>,
+    ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+        let request = ctx.get_request();
+        log::info!(
+            "{:?} {} {}",
+            request.get_method(),
+            request.get_path(),
+            ctx.get_response().get_status_code().unwrap_or(500)
+        );
+        Ok(ctx)
+    }
+
+    fn on_error(&self, request: &HttpRequest, error: ServerError) -> ServerError {
+        log::warn!("{:?} {} failed: {}", request.get_method(), request.get_path(), error.get_detail());
+        error
+    }
+}
+
 fn root_route(
     ctx: RequestContext<HttpRequest, HttpResponse>,
 ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
@@ -149,7 +175,7 @@ fn files_get_route(
         return Err(StdServerError::NotFound.to_error());
     }
 
-    let mut file_contents = String::new();
+    let mut file_contents = Vec::<u8>::new();
     let mut fh = match std::fs::File::open(file_path) {
         Ok(val) => val,
         Err(e) => {
@@ -157,7 +183,7 @@ fn files_get_route(
             return Err(StdServerError::InternalServerError.to_error());
         }
     };
-    match fh.read_to_string(&mut file_contents) {
+    match fh.read_to_end(&mut file_contents) {
         Ok(_) => {},
         Err(e) => {
             log::error!("{:?}", e);
@@ -209,7 +235,7 @@ fn files_post_route(
             return Err(StdServerError::InternalServerError.to_error());
         }
     };
-    match fh.write_all(file_contents.as_bytes()) {
+    match fh.write_all(&file_contents) {
         Ok(_) => {},
         Err(e) => {
             log::error!("{:?}", e);
@@ -225,17 +251,54 @@ fn files_post_route(
     Ok(ctx)
 }
 
+/// Sockets passed by systemd or a supervisor are used instead of binding our own.
+/// Otherwise listen on a Unix socket as well as TCP when one is given.
+#[cfg(unix)]
+fn get_listeners() -> Vec<Listen> {
+    let inherited = match listener::systemd_listeners() {
+        Ok(val) => val,
+        Err(e) => {
+            log::error!("Ignoring sockets passed by systemd: {:?}", e);
+            Vec::new()
+        }
+    };
+    // Not passed on to child processes, no other thread is running yet to read the environment
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if !inherited.is_empty() {
+        return inherited;
+    }
+    if let Some(fd) = get_arg("--listen-fd").and_then(|i| i.parse().ok()) {
+        // Safety: the supervisor hands the descriptor over to this process, nothing else in it uses the descriptor
+        return vec![Listen::Fd(unsafe { OwnedFd::from_raw_fd(fd) })];
+    }
+    match get_arg("--unix-socket") {
+        Some(path) => vec![Listen::tcp("127.0.0.1:4221"), Listen::unix_with_mode(path, 0o660)],
+        None => Vec::new(),
+    }
+}
+
+/// Inherited sockets and Unix sockets need a Unix platform, only the default TCP listener is used elsewhere.
+#[cfg(not(unix))]
+fn get_listeners() -> Vec<Listen> {
+    Vec::new()
+}
+
 fn get_directory() -> String {
+    get_arg("--directory").unwrap_or_default()
+}
+
+/// Get the value following a command line flag
+fn get_arg(name: &str) -> Option<String> {
     let mut is_next = false;
-    let mut directory = String::new();
     for arg in std::env::args() {
         if is_next {
-            directory = arg;
-            break;
+            return Some(arg);
         }
-        if arg == "--directory" {
+        if arg == name {
             is_next = true;
         }
     }
-    directory
+    None
 }
\ No newline at end of file
@@ -1,182 +1,945 @@
+use crate::http::types::{HttpMethod, HttpVersion};
+use crate::http::uri;
 use crate::server::context::RequestContext;
 use crate::server::error::{ServerError, StdServerError};
+use crate::server::limit::{Limit, Permit};
+pub use crate::server::limit::OverflowPolicy;
+use crate::server::listener::{AsyncStream, Listen, Listener, LocalAddr, Stream};
+use crate::server::middleware::Chain;
 use crate::server::parse;
+use crate::server::raw::RawStream;
+use crate::server::reader::{
+    AsyncRequestReader, BodyLimit, ReadTimeout, ReadTimeouts, RequestLimits, RequestMessage, RequestReader,
+};
 use crate::server::routing::Router;
-use crate::server::traits::{Request, RequestMiddleware, Response};
+use crate::server::runtime;
+use crate::server::shutdown::{ConnectionGuard, Connections, ShutdownHandle};
+use crate::server::tls::{self, TlsConfig};
+use crate::server::traits::{ConnectionInfo, RawConnection, RawMiddleware, Request, RequestMiddleware, Response};
 
-use std::thread;
-use std::sync::Arc;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::future::Future;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc as async_mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Sleep;
 
 pub struct ServerConfig {
     pub address: String,
     pub port: usize,
+    /// Sockets to listen on, when empty the server listens on `address` and `port`
+    pub listeners: Vec<Listen>,
+    /// How long a connection is kept open waiting for the next request to start
+    pub keep_alive_timeout: Duration,
+    /// How long a client has to send the request line and headers once a request has started
+    pub header_read_timeout: Duration,
+    /// How long a client has to send the body once the headers have arrived
+    pub body_read_timeout: Duration,
+    /// How long writing to the client may stall before the connection is dropped
+    pub write_timeout: Duration,
+    /// Longest request line accepted, longer ones are answered with 414
+    pub max_request_line: usize,
+    /// Most bytes of header fields accepted, more are answered with 431
+    pub max_header_bytes: usize,
+    /// Most header fields accepted, more are answered with 431
+    pub max_headers: usize,
+    /// Largest request body accepted, larger ones are answered with 413. Routes can override it.
+    pub max_body_size: usize,
+    /// Number of threads serving connections in the blocking server.
+    /// Each open connection holds a worker, idle keep-alive connections are closed
+    /// to free theirs when connections are waiting for one.
+    pub workers: usize,
+    /// Accepted connections that can wait for a free worker in the blocking server
+    pub accept_queue: usize,
+    /// What the server does with connections and requests over the limits,
+    /// including connections that find the blocking server's accept queue full
+    pub overflow: OverflowPolicy,
+    /// Most connections open at once, including those waiting for a worker. `None` for no limit.
+    pub max_connections: Option<usize>,
+    /// Most requests being handled at once across all connections. `None` for no limit.
+    pub max_requests: Option<usize>,
+    /// Sent as `Retry-After` with `503 Service Unavailable` when the server is at capacity
+    pub retry_after: Duration,
+    /// How long in-flight requests are given to finish once shutdown is requested
+    pub shutdown_timeout: Duration,
+    /// Serve HTTPS with these certificates instead of plaintext HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("127.0.0.1"),
+            port: 4221,
+            listeners: Vec::new(),
+            keep_alive_timeout: Duration::from_secs(5),
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_request_line: 8 * 1024,
+            max_header_bytes: 32 * 1024,
+            max_headers: 100,
+            max_body_size: 1024 * 1024,
+            workers: 16,
+            accept_queue: 64,
+            overflow: OverflowPolicy::Wait,
+            max_connections: None,
+            max_requests: None,
+            retry_after: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reject settings the server can't run with, sockets don't accept a zero timeout
+    fn validate(&self) -> io::Result<()> {
+        let timeouts = [
+            ("keep_alive_timeout", self.keep_alive_timeout),
+            ("header_read_timeout", self.header_read_timeout),
+            ("body_read_timeout", self.body_read_timeout),
+            ("write_timeout", self.write_timeout),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout.is_zero() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("{} must be longer than zero", name)));
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Application<T: Request, R: Response> {
     config: ServerConfig,
-    router: Router<T, R>,
-    // how do different types of middleware work? box + dyn?
-    // middleware: Option<Vec<Arc<dyn RequestMiddleware<T, R> + 'static>>>,
+    router: Arc<Router<T, R>>,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+    connection_limit: Limit,
+    request_limit: Limit,
+    /// Loaded from `config.tls` when serving starts
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Runs around every request, in the order it was added
+    middleware: Vec<Arc<dyn RequestMiddleware<T, R>>>,
+    /// Sees the bytes of every connection, in the order it was added
+    raw_middleware: Vec<Arc<dyn RawMiddleware>>,
+    /// Id given to the next connection raw middleware is asked to handle
+    next_connection: AtomicU64,
 }
 
 impl<T: Request, R: Response> Application<T, R> {
     pub fn new(
         config: ServerConfig,
         router: Router<T, R>,
-        // middleware: Option<Vec<Arc<dyn RequestMiddleware<T, R> +'static>>>,
     ) -> Self {
+        let connection_limit = Limit::new(config.max_connections, config.overflow);
+        let request_limit = Limit::new(config.max_requests, config.overflow);
         Self {
-            config: config,
-            router: router,
-            // middleware: middleware,
+            config,
+            router: Arc::new(router),
+            shutdown: ShutdownHandle::new(),
+            connections: Arc::new(Connections::default()),
+            connection_limit,
+            request_limit,
+            tls: None,
+            middleware: Vec::new(),
+            raw_middleware: Vec::new(),
+            next_connection: AtomicU64::new(0),
         }
     }
+
+    /// Add middleware that runs around every request.
+    /// `on_request` runs in the order middleware is added and `on_response` in reverse,
+    /// so the first middleware added sees the request first and the response last.
+    pub fn with_middleware<M: RequestMiddleware<T, R> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Add middleware that sees the bytes read from and written to every connection.
+    /// It is asked for the state of each connection as it is accepted, over TLS it sees the encrypted bytes.
+    /// `on_request` runs in the order middleware is added and `on_response` in reverse.
+    pub fn with_raw_middleware<M: RawMiddleware + 'static>(mut self, middleware: M) -> Self {
+        self.raw_middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Handle that stops the server once it is serving
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Load the TLS certificates, if the server is configured to use them
+    fn load_tls(&mut self) -> io::Result<()> {
+        self.tls = match &self.config.tls {
+            Some(val) => Some(tls::server_config(val)?),
+            None => None,
+        };
+        Ok(())
+    }
 }
 
-impl<T: Request, R: Response> Application<T, R> {
+impl<T: Request + 'static, R: Response + 'static> Application<T, R> {
     fn get_bind(&self) -> String {
-        format!("{:}:{:?}", self.config.address, self.config.port)
+        // IPv6 literals need brackets to be told apart from the port
+        if self.config.address.contains(':') && !self.config.address.starts_with('[') {
+            format!("[{:}]:{:?}", self.config.address, self.config.port)
+        } else {
+            format!("{:}:{:?}", self.config.address, self.config.port)
+        }
     }
 
-    fn handle(&self, buffer: String) -> Result<RequestContext<T, R>, ServerError> {
-        let mut ctx = RequestContext::<T, R>::new();
+    /// Listeners to bind, moved out of the configuration since inherited sockets can only be taken once
+    fn take_listens(&mut self) -> Vec<Listen> {
+        if self.config.listeners.is_empty() {
+            vec![Listen::Tcp(self.get_bind())]
+        } else {
+            std::mem::take(&mut self.config.listeners)
+        }
+    }
 
-        // Parse request
-        match parse::parse_into_request(buffer) {
-            Ok(val) => {
-                ctx.set_request(val);
-            }
-            Err(e) => return Err(e),
+    fn limits(&self) -> RequestLimits {
+        RequestLimits {
+            request_line: self.config.max_request_line,
+            header_bytes: self.config.max_header_bytes,
+            headers: self.config.max_headers,
+            body: self.config.max_body_size,
         }
+    }
 
-        // Execute middleware pre request
-        // match &self.middleware {
-        //     Some(val) => {
-        //         for middleware in val.iter() {
-        //             match middleware.on_request(ctx) {
-        //                 Ok(val) => {
-        //                     ctx = val;
-        //                 }
-        //                 Err(e) => return Err(e),
-        //             }
-        //         }
-        //     }
-        //     None => {}
-        // }
+    /// Body size limit of the route each request is dispatched to
+    fn body_limit(&self) -> BodyLimit {
+        let router = Arc::clone(&self.router);
+        Arc::new(move |method, target| {
+            let method = HttpMethod::from_str(method).ok()?;
+            let (path, _) = uri::split_target(target);
+            router.body_limit(&method, path)
+        })
+    }
 
-        // Dispatch route handler
-        ctx = match self.router.dispatch(ctx) {
-            Ok(val) => val,
-            Err(e) => return Err(e),
+    fn read_timeouts(&self) -> ReadTimeouts {
+        ReadTimeouts {
+            idle: self.config.keep_alive_timeout,
+            header: self.config.header_read_timeout,
+            body: self.config.body_read_timeout,
+        }
+    }
+
+    /// Raw middleware state for a newly accepted connection
+    fn raw_connections(&self, peer_addr: Option<SocketAddr>) -> Vec<Box<dyn RawConnection>> {
+        if self.raw_middleware.is_empty() {
+            return Vec::new();
+        }
+        let info = ConnectionInfo {
+            id: self.next_connection.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
         };
+        self.raw_middleware.iter().map(|i| i.connection(&info)).collect()
+    }
 
-        // Execute middleware pre response
-        // This means errors wont go through this middleware...
-        // match &self.middleware {
-        //     Some(val) => {
-        //         for middleware in val.iter() {
-        //             match middleware.on_request(ctx) {
-        //                 Ok(val) => {
-        //                     ctx = val;
-        //                 }
-        //                 Err(e) => return Err(e),
-        //             }
-        //         }
-        //     }
-        //     None => {}
-        // }
+    /// Answer an error raised outside the middleware chain, such as a malformed request or the server
+    /// being at capacity. It still goes through `on_error` and `on_response` of the application middleware.
+    fn error_context(&self, error: ServerError, version: HttpVersion) -> RequestContext<T, R> {
+        let request = || {
+            let mut request = T::new();
+            request.set_version(version);
+            request
+        };
+        let result = runtime::run_blocking(|| Chain::reject(&self.middleware, request(), error));
+        result.unwrap_or_else(|e| {
+            let mut ctx = RequestContext::new();
+            ctx.set_response(parse::error_into_response(&e));
+            ctx.set_request(request());
+            ctx
+        })
+    }
 
-        Ok(ctx)
+    /// Write the answer to an error raised outside the middleware chain, the connection is closed after it
+    fn send_error_context<W: Write>(&self, stream: &mut W, error: ServerError, version: HttpVersion) {
+        let ctx = self.error_context(error, version);
+        let mut response = ctx.get_response().clone();
+        set_connection_header(version, false, &mut response);
+        send_response(stream, ctx.get_request(), &response);
     }
 
-    fn handle_stream(&self, stream: TcpStream) {
-        let mut stream = stream;
+    async fn send_error_context_async<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        error: ServerError,
+        version: HttpVersion,
+    ) {
+        let ctx = self.error_context(error, version);
+        let mut response = ctx.get_response().clone();
+        set_connection_header(version, false, &mut response);
+        send_response_async(writer, ctx.get_request(), &response).await;
+    }
 
-        let raw = match read_stream(&stream) {
-            Ok(val) => val,
-            Err(e) => {
-                panic!("failed read stream");
-            }, 
-        };
-        
-        log::debug!("Buf String: {}", &raw);
+    /// Error sent when the server is at capacity
+    fn overloaded(&self) -> ServerError {
+        // Round up, a client retrying early would be turned away again
+        let retry_after = self.config.retry_after;
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        StdServerError::ServiceUnavailable
+            .to_error()
+            .with_header("Retry-After", &retry_after.to_string())
+    }
+
+    /// Take a connection slot for a blocking acceptor, giving up if shutdown is requested while waiting
+    fn admit_connection(&self) -> Option<Permit> {
+        runtime::block_on(async {
+            tokio::select! {
+                val = self.connection_limit.acquire() => val,
+                _ = self.shutdown.wait() => None,
+            }
+        })
+    }
 
-        let response = match self.handle(raw) {
-            Ok(val) => parse::serialize_into_response(val.get_response()),
-            Err(e) => parse::serialize_error_into_response(e),
+    /// Turn a connection away because the server is at capacity
+    fn reject_connection(&self, stream: &Stream) {
+        log::warn!("Server is at capacity, rejecting connection.");
+        // Rejected connections can't be answered before a TLS handshake, so they are only closed
+        if self.tls.is_none() {
+            if let Err(e) = stream.set_write_timeout(Some(self.config.write_timeout)) {
+                log::debug!("Failed to set the connection write timeout: {:?}", e);
+            }
+            // Nothing has been read, the client's version isn't known
+            let mut stream = RawStream::new(stream, self.raw_connections(stream.peer_addr()));
+            send_error(&mut stream, self.overloaded(), HttpVersion::V1_1);
+        }
+        close_connection(stream);
+    }
+
+    async fn reject_connection_async(&self, stream: AsyncStream) {
+        log::warn!("Server is at capacity, rejecting connection.");
+        // Rejected connections can't be answered before a TLS handshake, so they are only closed
+        let raw = match self.tls {
+            Some(_) => Vec::new(),
+            None => self.raw_connections(stream.peer_addr()),
         };
+        let mut stream = TimeoutWriter::new(RawStream::new(stream, raw), self.config.write_timeout);
+        if self.tls.is_none() {
+            send_error_async(&mut stream, self.overloaded(), HttpVersion::V1_1).await;
+        }
+        if let Err(e) = stream.shutdown().await {
+            println!("error closing the connection: {}", e);
+        }
+    }
+
+    /// Take a request slot, it is held until the response has been written
+    async fn admit_request(&self) -> Result<Permit, ServerError> {
+        self.request_limit.acquire().await.ok_or_else(|| self.overloaded())
+    }
 
-        send_response(&mut stream, response);
-        close_connection(&mut stream);
+    fn handle(&self, message: RequestMessage) -> Result<RequestContext<T, R>, ServerError> {
+        runtime::block_on(self.handle_async(message))
+    }
+
+    async fn handle_async(&self, message: RequestMessage) -> Result<RequestContext<T, R>, ServerError> {
+        let mut ctx = RequestContext::<T, R>::new();
+
+        // Parse request, a request that can't be served still goes through the middleware
+        let version = message.response_version();
+        match parse::parse_into_request(message) {
+            Ok(val) => ctx.set_request(val),
+            Err(e) => return Ok(self.error_context(e, version)),
+        }
+
+        let mut chain = Chain::default();
+        let result = async {
+            // Execute middleware pre request, like sync handlers it may block
+            let ctx = runtime::run_blocking(|| chain.enter(&self.middleware, ctx))?;
+            if ctx.is_finished() {
+                return Ok(ctx);
+            }
+
+            // Dispatch route handler, route middleware joins the chain
+            self.router.dispatch_chained(ctx, &mut chain).await
+        }
+        .await;
+
+        // Execute middleware pre response, innermost first, errors are turned into responses for it
+        runtime::run_blocking(|| chain.leave(result))
+    }
+
+    fn handle_stream(&self, stream: Stream) {
+        let connection = self.connections.register(&stream);
+        if let Err(e) = stream.set_write_timeout(Some(self.config.write_timeout)) {
+            log::error!("Failed to set the connection write timeout: {:?}", e);
+            close_connection(&stream);
+            return;
+        }
+
+        // Raw middleware sits above TLS, it sees the bytes requests are read from and responses written as
+        match &self.tls {
+            Some(config) => match tls::accept(config, &stream, self.config.header_read_timeout) {
+                Ok(val) => {
+                    let raw = RawStream::new(val, self.raw_connections(stream.peer_addr()));
+                    let raw = self.serve_stream(raw, &connection);
+                    tls::close(raw.into_inner());
+                }
+                Err(e) => log::debug!("TLS handshake failed: {:?}", e),
+            },
+            None => {
+                let raw = RawStream::new(&stream, self.raw_connections(stream.peer_addr()));
+                self.serve_stream(raw, &connection);
+            }
+        }
+
+        close_connection(&stream);
+    }
+
+    /// Serve requests from a connection until it closes, returns the stream so it can be shut down
+    fn serve_stream<S: Read + Write + ReadTimeout>(&self, stream: S, connection: &Option<ConnectionGuard>) -> S {
+        let mut reader =
+            RequestReader::new(stream, self.read_timeouts(), self.limits()).with_body_limit(self.body_limit());
+
+        // Serve requests until either side asks to close or the connection goes idle
+        loop {
+            // Idle connections stop reading once shutdown is requested, a request being received is finished
+            connection.iter().for_each(|i| i.set_idle(true));
+            let started = reader.wait_for_request();
+            connection.iter().for_each(|i| i.set_idle(false));
+
+            let message = match started {
+                Ok(true) => reader.read_request(),
+                Ok(false) => Ok(None),
+                Err(e) => Err(e),
+            };
+            let message = match message {
+                Ok(Some(val)) => val,
+                Ok(None) => break,
+                Err(e) => {
+                    let version = reader.version();
+                    self.send_error_context(reader.get_mut(), e, version);
+                    break;
+                }
+            };
+
+            log::debug!("{} {}", message.request_line.method, message.request_line.target);
+
+            // The request slot is held until the response has been written
+            let (result, _permit) = match runtime::block_on(self.admit_request()) {
+                Ok(permit) => (self.handle(message), Some(permit)),
+                Err(e) => (Ok(self.error_context(e, message.response_version())), None),
+            };
+            let keep_alive = match result {
+                Ok(val) => {
+                    let mut response = val.get_response().clone();
+                    let version = val.get_request().get_version();
+                    // Give the worker up after this response if other connections are waiting for one
+                    let keep_alive = is_keep_alive(val.get_request(), &response)
+                        && !self.shutdown.is_shutdown()
+                        && !self.connections.is_contended();
+                    set_connection_header(version, keep_alive, &mut response);
+                    send_response(reader.get_mut(), val.get_request(), &response) && keep_alive
+                }
+                Err(e) => {
+                    let version = reader.version();
+                    send_error(reader.get_mut(), e, version);
+                    false
+                }
+            };
+
+            if !keep_alive {
+                break;
+            }
+        }
+
+        reader.into_inner()
+    }
+}
+
+impl<T: Request + 'static, R: Response + 'static> Application<T, R> {
+    async fn handle_connection(&self, stream: AsyncStream) {
+        // Raw middleware sits above TLS, it sees the bytes requests are read from and responses written as
+        let peer_addr = stream.peer_addr();
+        match &self.tls {
+            Some(config) => {
+                let acceptor = tokio_rustls::TlsAcceptor::from(Arc::clone(config));
+                match tokio::time::timeout(self.config.header_read_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(val)) => self.serve_connection(RawStream::new(val, self.raw_connections(peer_addr))).await,
+                    Ok(Err(e)) => log::debug!("TLS handshake failed: {:?}", e),
+                    Err(_) => log::debug!("TLS handshake timed out"),
+                }
+            }
+            None => self.serve_connection(RawStream::new(stream, self.raw_connections(peer_addr))).await,
+        }
+    }
+
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let mut reader = AsyncRequestReader::new(read_half, self.read_timeouts(), self.limits())
+            .with_body_limit(self.body_limit());
+        let mut write_half = TimeoutWriter::new(write_half, self.config.write_timeout);
+
+        // Serve requests until either side asks to close or the connection goes idle
+        loop {
+            // Idle connections are closed once shutdown is requested, a request being received is finished
+            let idle = reader.is_idle();
+            let message = tokio::select! {
+                val = reader.read_request() => val,
+                _ = self.shutdown.wait(), if idle => break,
+            };
+            let message = match message {
+                Ok(Some(val)) => val,
+                Ok(None) => break,
+                Err(e) => {
+                    self.send_error_context_async(&mut write_half, e, reader.version()).await;
+                    break;
+                }
+            };
+
+            log::debug!("{} {}", message.request_line.method, message.request_line.target);
+
+            // The request slot is held until the response has been written
+            let (result, _permit) = match self.admit_request().await {
+                Ok(permit) => (self.handle_async(message).await, Some(permit)),
+                Err(e) => (Ok(self.error_context(e, message.response_version())), None),
+            };
+            let keep_alive = match result {
+                Ok(val) => {
+                    let mut response = val.get_response().clone();
+                    let version = val.get_request().get_version();
+                    let keep_alive =
+                        is_keep_alive(val.get_request(), &response) && !self.shutdown.is_shutdown();
+                    set_connection_header(version, keep_alive, &mut response);
+                    send_response_async(&mut write_half, val.get_request(), &response).await && keep_alive
+                }
+                Err(e) => {
+                    send_error_async(&mut write_half, e, reader.version()).await;
+                    false
+                }
+            };
+
+            if !keep_alive {
+                break;
+            }
+        }
+
+        if let Err(e) = write_half.shutdown().await {
+            println!("error closing the connection: {}", e);
+        }
     }
 }
 
+/// Load the TLS certificates and bind every listener without serving yet.
+/// The bound addresses can be read from the returned server, such as the port picked for port 0.
+pub fn bind<T: Request + 'static, R: Response + 'static>(mut application: Application<T, R>) -> io::Result<Server<T, R>> {
+    application.config.validate()?;
+    application.load_tls()?;
+    let listeners = application
+        .take_listens()
+        .into_iter()
+        .map(|listen| {
+            let name = listen.to_string();
+            Listener::bind(listen)
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to listen on {}: {}", name, e)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(Server {
+        application: Arc::new(application),
+        listeners,
+    })
+}
+
+/// Serve the application with a fixed pool of worker threads, each handling one connection at a time.
+/// Idle keep-alive connections are closed when connections are waiting for a worker.
+/// Returns once shutdown is requested and the open connections have finished.
 pub fn serve<T: Request + 'static, R: Response + 'static>(application: Application<T, R>) {
-    // let application = application;
-    let application = Arc::new(application);
-    // let application = Arc::<&Application<T, R>>::new(&application);
+    let bind = application.get_bind();
+    match self::bind(application) {
+        Ok(val) => val.serve(),
+        Err(e) => {
+            println!("Error starting server: {:?}", e);
+            panic!("Failed to start server on {:?}", bind);
+        }
+    }
+}
 
-    let listener = match TcpListener::bind(application.get_bind()) {
-        Ok(val) => val,
+/// Serve the application on a tokio runtime, each connection is handled by a task.
+/// Route handlers can be async, sync handlers run as blocking code on the runtime.
+/// Returns once shutdown is requested and the open connections have finished.
+pub async fn serve_async<T: Request + 'static, R: Response + 'static>(application: Application<T, R>) {
+    let bind = application.get_bind();
+    match self::bind(application) {
+        Ok(val) => val.serve_async().await,
         Err(e) => {
             println!("Error starting server: {:?}", e);
-            panic!("Failed to start server on {:?}", application.get_bind());
+            panic!("Failed to start server on {:?}", bind);
         }
-    };
+    }
+}
+
+/// Application with its listeners bound, ready to serve
+pub struct Server<T: Request, R: Response> {
+    application: Arc<Application<T, R>>,
+    listeners: Vec<Listener>,
+}
+
+impl<T: Request + 'static, R: Response + 'static> Server<T, R> {
+    /// Addresses the listeners are bound to, in the order they were configured
+    pub fn local_addrs(&self) -> Vec<LocalAddr> {
+        self.listeners.iter().filter_map(|i| i.local_addr().ok()).collect()
+    }
+
+    /// Handle that stops the server once it is serving
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.application.shutdown_handle()
+    }
+
+    /// Serve with a fixed pool of worker threads, see `serve`
+    pub fn serve(self) {
+        let Server {
+            application,
+            listeners,
+        } = self;
+
+        // Wake the acceptors with connections of our own, then close idle keep-alive connections
+        let addresses = listeners.iter().filter_map(|i| i.local_addr().ok()).collect::<Vec<_>>();
+        addresses.iter().for_each(|i| log::info!("Listening on {}", i));
+        let connections = Arc::clone(&application.connections);
+        application.shutdown.on_shutdown(move || {
+            addresses.iter().for_each(|i| i.poke());
+            connections.close_idle();
+        });
+
+        let pool = WorkerPool::new(Arc::clone(&application));
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(val) => {
-                println!("accepted new connection");
-                let arc = Arc::clone(&application);
-                thread::spawn(move || {
-                    arc.handle_stream(val);
+        // Every listener has its own acceptor thread feeding the shared worker pool
+        thread::scope(|scope| {
+            for listener in listeners.iter() {
+                let application = &application;
+                let pool = &pool;
+                scope.spawn(move || loop {
+                    let stream = listener.accept();
+                    if application.shutdown.is_shutdown() {
+                        break;
+                    }
+                    match stream {
+                        Ok(val) => {
+                            println!("accepted new connection");
+                            // Under `Wait` this acceptor stops until a connection closes, so new ones stay in the backlog
+                            match application.admit_connection() {
+                                Some(permit) => pool.dispatch(val, permit),
+                                None => application.reject_connection(&val),
+                            }
+                        }
+                        Err(e) => {
+                            println!("error: {}", e);
+                        }
+                    }
                 });
             }
-            Err(e) => {
-                println!("error: {}", e);
+        });
+
+        drop(listeners);
+        log::info!("Shutting down, waiting for open connections to finish.");
+        application.connections.drain(application.config.shutdown_timeout);
+        pool.join();
+    }
+
+    /// Serve on a tokio runtime, see `serve_async`
+    pub async fn serve_async(self) {
+        let Server {
+            application,
+            listeners,
+        } = self;
+
+        // Every listener has its own acceptor task handing connections to the loop below
+        let (sender, mut accepted) = async_mpsc::channel::<(AsyncStream, Permit)>(1);
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let listener = match listener.into_async() {
+                Ok(val) => val,
+                Err(e) => {
+                    println!("Error starting server: {:?}", e);
+                    panic!("Failed to start server on {:?}", application.get_bind());
+                }
+            };
+            if let Ok(address) = listener.local_addr() {
+                log::info!("Listening on {}", address);
             }
+            let sender = sender.clone();
+            let application = Arc::clone(&application);
+            acceptors.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        // Under `Wait` this acceptor stops until a connection closes, so new ones stay in the backlog
+                        Ok(val) => match application.connection_limit.acquire().await {
+                            Some(permit) => {
+                                if sender.send((val, permit)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            None => {
+                                let arc = Arc::clone(&application);
+                                tokio::spawn(async move { arc.reject_connection_async(val).await });
+                            }
+                        },
+                        Err(e) => {
+                            println!("error: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, permit) = tokio::select! {
+                Some(val) = accepted.recv() => val,
+                // Finished connections are collected as they go
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = application.shutdown.wait() => break,
+            };
+            println!("accepted new connection");
+            let arc = Arc::clone(&application);
+            connections.spawn(async move {
+                arc.handle_connection(stream).await;
+                drop(permit);
+            });
+        }
+
+        // Stopping the acceptors closes the listeners
+        acceptors.shutdown().await;
+        log::info!("Shutting down, waiting for open connections to finish.");
+        let drained = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(application.config.shutdown_timeout, drained).await.is_err() {
+            log::warn!("Closing {} connections still open at the shutdown deadline.", connections.len());
+            connections.shutdown().await;
         }
     }
 }
 
-fn read_stream(stream: &TcpStream) -> Result<String, ServerError> {
-    const BUF_SIZE: usize = 100;
-    let mut result = String::new();
-    let mut reader = BufReader::new(stream);
-    let mut buffer: [u8; BUF_SIZE] = [0; BUF_SIZE];
+/// Worker threads fed from a bounded queue of accepted connections
+struct WorkerPool<T: Request, R: Response> {
+    sender: SyncSender<(Stream, Permit)>,
+    application: Arc<Application<T, R>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Request + 'static, R: Response + 'static> WorkerPool<T, R> {
+    fn new(application: Arc<Application<T, R>>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<(Stream, Permit)>(application.config.accept_queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let count = application.config.workers.max(1);
+        application.connections.set_workers(count);
+        let workers = (0..count)
+            .map(|_| {
+                let application = Arc::clone(&application);
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting for the next connection
+                    let stream = match receiver.lock() {
+                        Ok(val) => val.recv(),
+                        Err(_) => break,
+                    };
+                    match stream {
+                        // The connection slot is given back once the connection closes.
+                        // A panicking handler only loses its connection, the worker goes on to the next one.
+                        Ok((val, _permit)) => {
+                            if panic::catch_unwind(AssertUnwindSafe(|| application.handle_stream(val))).is_err() {
+                                log::error!("A worker panicked serving a connection, the connection was dropped.");
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
 
-    // read buffer until less than BUF_SIZE bytes have been read
-    loop {
-        match reader.read(&mut buffer[..]) {
-            Ok(val) => {
-                for n in 0..(val) {
-                    result.push(char::from(buffer[n]));
+        Self {
+            sender,
+            application,
+            workers,
+        }
+    }
+
+    /// Queue a connection for the next free worker
+    fn dispatch(&self, stream: Stream, permit: Permit) {
+        // Counted before it is sent, a worker may take it straight away
+        let connections = &self.application.connections;
+        connections.queue();
+        match self.application.config.overflow {
+            OverflowPolicy::Wait => {
+                if self.sender.send((stream, permit)).is_err() {
+                    connections.unqueue();
+                    println!("error: no workers left to serve the connection");
                 }
-                if val != BUF_SIZE {
-                    break;
+            }
+            OverflowPolicy::Reject => match self.sender.try_send((stream, permit)) {
+                Ok(_) => {}
+                Err(TrySendError::Full((stream, _))) | Err(TrySendError::Disconnected((stream, _))) => {
+                    connections.unqueue();
+                    self.application.reject_connection(&stream);
                 }
             },
-            Err(_) => {
-                return Err(StdServerError::BadRequest.to_error());
-            },
         }
     }
-    Ok(result)
+
+    /// Stop handing out connections and wait for the workers to finish
+    fn join(self) {
+        drop(self.sender);
+        for worker in self.workers {
+            if worker.join().is_err() {
+                println!("error: a worker thread panicked");
+            }
+        }
+    }
 }
 
-fn send_response(stream: &mut TcpStream, response: String) {
-    match stream.write(response.as_bytes()) {
-        Ok(_) => {}
+/// Check whether a `Connection` header value lists the given option
+fn has_connection_option(value: Option<String>, option: &str) -> bool {
+    match value {
+        Some(val) => val
+            .split(',')
+            .any(|i| i.trim().eq_ignore_ascii_case(option)),
+        None => false,
+    }
+}
+
+/// Decide whether the connection stays open after this exchange.
+/// HTTP/1.1 is persistent unless either side sends `Connection: close`,
+/// HTTP/1.0 is only persistent when the client asks with `Connection: keep-alive`
+/// and the response body has a known length.
+fn is_keep_alive(request: &impl Request, response: &impl Response) -> bool {
+    if has_connection_option(response.get_header("Connection"), "close") {
+        return false;
+    }
+    let version = request.get_version();
+    let delimited_by_close = response.get_body().is_stream()
+        && !version.supports_chunked()
+        && response.get_header("Content-Length").is_none();
+    if delimited_by_close {
+        return false;
+    }
+    let connection = request.get_header("Connection");
+    if version.is_persistent_by_default() {
+        !has_connection_option(connection, "close")
+    } else {
+        has_connection_option(connection, "keep-alive")
+    }
+}
+
+fn set_connection_header(version: HttpVersion, keep_alive: bool, response: &mut impl Response) {
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    } else if !version.is_persistent_by_default() {
+        response.set_header("Connection", "keep-alive");
+    }
+}
+
+/// Write the response to the client, returns false if the connection is no longer usable
+/// or the response written closes it
+fn send_response<W: Write>(stream: &mut W, request: &impl Request, response: &impl Response) -> bool {
+    match parse::write_response(stream, request, response) {
+        Ok(closed) => !closed,
         Err(e) => {
             println!("Error sending the response: {:?}", e);
+            false
+        }
+    }
+}
+
+fn send_error<W: Write>(stream: &mut W, error: ServerError, version: HttpVersion) {
+    let response = parse::serialize_error_into_response(error, version);
+    if let Err(e) = stream.write_all(response.as_bytes()).and_then(|_| stream.flush()) {
+        println!("Error sending the response: {:?}", e);
+    }
+}
+
+async fn send_response_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    request: &impl Request,
+    response: &impl Response,
+) -> bool {
+    match parse::write_response_async(writer, request, response).await {
+        Ok(closed) => !closed,
+        Err(e) => {
+            println!("Error sending the response: {:?}", e);
+            false
+        }
+    }
+}
+
+async fn send_error_async<W: AsyncWrite + Unpin>(writer: &mut W, error: ServerError, version: HttpVersion) {
+    let response = parse::serialize_error_into_response(error, version);
+    if let Err(e) = writer.write_all(response.as_bytes()).await {
+        println!("Error sending the response: {:?}", e);
+    }
+}
+
+/// Writer that fails a write or flush which makes no progress for `timeout`
+struct TimeoutWriter<W: AsyncWrite + Unpin> {
+    inner: W,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W: AsyncWrite + Unpin> TimeoutWriter<W> {
+    fn new(inner: W, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: None,
+        }
+    }
+
+    /// Track how long the inner writer has been pending, called when it is not ready
+    fn poll_timeout<O>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<O>> {
+        let timeout = self.timeout;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(_) => {
+                self.sleep = None;
+                Poll::Ready(Err(io::Error::new(ErrorKind::TimedOut, "write timed out")))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TimeoutWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(val) => {
+                this.sleep = None;
+                Poll::Ready(val)
+            }
+            Poll::Pending => this.poll_timeout(cx),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_flush(cx) {
+            Poll::Ready(val) => {
+                this.sleep = None;
+                Poll::Ready(val)
+            }
+            Poll::Pending => this.poll_timeout(cx),
         }
     }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
 }
 
-fn close_connection(stream: &mut TcpStream) {
+fn close_connection(stream: &Stream) {
     match stream.shutdown(Shutdown::Both) {
         Ok(_) => {}
         Err(e) => {
@@ -184,3 +947,1092 @@ fn close_connection(stream: &mut TcpStream) {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::http::types::HttpMethod;
+    use crate::server::context::{HttpRequest, HttpResponse};
+    use crate::server::raw::tests::ByteCounter;
+    use crate::server::reader::tests::read_message;
+    use crate::server::routing::Route;
+    use crate::server::tls::tests::TestCertificate;
+    use crate::server::traits::Error;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::Ordering;
+
+    fn test_application() -> Application<HttpRequest, HttpResponse> {
+        test_application_with(ServerConfig::default())
+    }
+
+    fn test_application_with(config: ServerConfig) -> Application<HttpRequest, HttpResponse> {
+        let router = Router::new(vec![
+            Route::new(
+                String::from("/"),
+                Box::new(|mut ctx: RequestContext<HttpRequest, HttpResponse>| {
+                    let mut response = HttpResponse::new();
+                    response.set_status_code(200);
+                    response.set_body(String::from("ok"));
+                    ctx.set_response(response);
+                    Ok(ctx)
+                }),
+                vec![HttpMethod::Get],
+            ),
+            Route::new_async(
+                String::from("/async"),
+                Box::new(|mut ctx: RequestContext<HttpRequest, HttpResponse>| {
+                    Box::pin(async move {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        let mut response = HttpResponse::new();
+                        response.set_status_code(200);
+                        response.set_body(String::from("async"));
+                        ctx.set_response(response);
+                        Ok(ctx)
+                    })
+                }),
+                vec![HttpMethod::Get],
+            ),
+            Route::new(
+                String::from("/panic"),
+                Box::new(|_: RequestContext<HttpRequest, HttpResponse>| panic!("handler failed")),
+                vec![HttpMethod::Get],
+            ),
+            // Forgets to set a status code
+            Route::new(String::from("/unset"), Box::new(Ok), vec![HttpMethod::Get]),
+            Route::new(
+                String::from("/large"),
+                Box::new(|mut ctx: RequestContext<HttpRequest, HttpResponse>| {
+                    // More than the socket buffers hold, writing it waits for the client to read
+                    let mut response = HttpResponse::new();
+                    response.set_status_code(200);
+                    response.set_body("x".repeat(16 * 1024 * 1024));
+                    ctx.set_response(response);
+                    Ok(ctx)
+                }),
+                vec![HttpMethod::Get],
+            ),
+            Route::new_async(
+                String::from("/sleep/{ms}"),
+                Box::new(|mut ctx: RequestContext<HttpRequest, HttpResponse>| {
+                    Box::pin(async move {
+                        let ms = ctx.get_request().get_path_param("ms").unwrap_or_default();
+                        tokio::time::sleep(Duration::from_millis(ms.parse().unwrap_or(0))).await;
+                        let mut response = HttpResponse::new();
+                        response.set_status_code(200);
+                        response.set_body(String::from("slept"));
+                        ctx.set_response(response);
+                        Ok(ctx)
+                    })
+                }),
+                vec![HttpMethod::Get],
+            ),
+        ]);
+        Application::new(config, router)
+    }
+
+    /// Serve a single connection with the test application and return the client side
+    fn connect() -> TcpStream {
+        connect_with(ServerConfig::default())
+    }
+
+    fn connect_with(config: ServerConfig) -> TcpStream {
+        connect_application(test_application_with(config))
+    }
+
+    fn connect_application(application: Application<HttpRequest, HttpResponse>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            application.handle_stream(Stream::Tcp(stream));
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut buffer = [0; 1024];
+        let n = stream.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[test]
+    fn test_keep_alive_serves_sequential_requests() {
+        let mut stream = connect();
+
+        for _ in 0..3 {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let response = read_response(&mut stream);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("\r\n\r\nok"));
+            assert!(!response.contains("Connection: close"));
+        }
+    }
+
+    #[test]
+    fn test_connection_close_ends_connection() {
+        let mut stream = connect();
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(read_response(&mut stream), "");
+    }
+
+    #[test]
+    fn test_response_without_status_closes_connection() {
+        let mut stream = connect();
+
+        stream.write_all(b"GET /unset HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        // The server does what the 500 announced instead of waiting for the next request
+        assert_eq!(read_response(&mut stream), "");
+    }
+
+    #[test]
+    fn test_http10_keep_alive_is_opt_in() {
+        let mut stream = connect();
+
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Connection: keep-alive\r\n"));
+
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: close\r\n"));
+        assert_eq!(read_response(&mut stream), "");
+    }
+
+    #[test]
+    fn test_blocking_server_runs_async_routes() {
+        let mut stream = connect();
+
+        stream.write_all(b"GET /async HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nasync"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connection_serves_pipelined_requests() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            test_application().handle_connection(AsyncStream::Tcp(stream)).await;
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /async HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nasync\
+             HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+
+    #[test]
+    fn test_worker_pool_rejects_when_queue_is_full() {
+        let config = ServerConfig {
+            workers: 1,
+            accept_queue: 1,
+            overflow: OverflowPolicy::Reject,
+            ..Default::default()
+        };
+        let application = Arc::new(test_application_with(config));
+        let pool = WorkerPool::new(Arc::clone(&application));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let accept = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let permit = application.admit_connection().unwrap();
+            pool.dispatch(Stream::Tcp(listener.accept().unwrap().0), permit);
+            stream
+        };
+
+        // The only worker is kept busy by a request still arriving
+        let mut first = accept();
+        first.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        // The next connection waits in the queue, the one after is turned away
+        let mut second = accept();
+        let mut third = accept();
+        let response = read_response(&mut third);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        // Once the worker is free the queued connection is served
+        first.write_all(b"Connection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut first).starts_with("HTTP/1.1 200 OK\r\n"));
+        second.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut second).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        pool.join();
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_handler() {
+        let config = ServerConfig {
+            workers: 1,
+            ..Default::default()
+        };
+        let application = Arc::new(test_application_with(config));
+        let pool = WorkerPool::new(Arc::clone(&application));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let accept = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let permit = application.admit_connection().unwrap();
+            pool.dispatch(Stream::Tcp(listener.accept().unwrap().0), permit);
+            stream
+        };
+
+        // Each panic only drops its own connection, the only worker is still there for the next ones
+        for _ in 0..3 {
+            let mut stream = accept();
+            stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(read_response(&mut stream), "");
+        }
+        let mut stream = accept();
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        pool.join();
+    }
+
+    #[test]
+    fn test_idle_connection_gives_up_worker() {
+        let config = ServerConfig {
+            workers: 1,
+            keep_alive_timeout: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let application = Arc::new(test_application_with(config));
+        let pool = WorkerPool::new(Arc::clone(&application));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let accept = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let permit = application.admit_connection().unwrap();
+            pool.dispatch(Stream::Tcp(listener.accept().unwrap().0), permit);
+            stream
+        };
+
+        // The only worker waits on a persistent connection for its next request
+        let mut first = accept();
+        first.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut first).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // A new connection doesn't wait for the keep-alive timeout, the idle one is closed for it
+        let mut second = accept();
+        second.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut second).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(read_response(&mut first), "");
+
+        pool.join();
+    }
+
+    /// Config listening on a port picked by the OS
+    fn ephemeral_config() -> ServerConfig {
+        ServerConfig {
+            port: 0,
+            ..Default::default()
+        }
+    }
+
+    /// First TCP address the server is bound to
+    fn tcp_addr<T: Request + 'static, R: Response + 'static>(server: &Server<T, R>) -> SocketAddr {
+        server.local_addrs()[0].as_tcp().unwrap()
+    }
+
+    fn connect_to(address: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    /// Send a request and read until the server closes the connection
+    fn exchange<S: Read + Write>(mut stream: S, request: &[u8]) -> String {
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_bind_rejects_zero_timeouts() {
+        let config = ServerConfig {
+            keep_alive_timeout: Duration::ZERO,
+            ..ephemeral_config()
+        };
+        let error = bind(test_application_with(config)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("keep_alive_timeout"));
+    }
+
+    #[test]
+    fn test_bind_reports_ephemeral_ports() {
+        let first = bind(test_application_with(ephemeral_config())).unwrap();
+        let second = bind(test_application_with(ephemeral_config())).unwrap();
+        let (first_addr, second_addr) = (tcp_addr(&first), tcp_addr(&second));
+        assert_ne!(first_addr.port(), 0);
+        assert_ne!(first_addr.port(), second_addr.port());
+
+        // Binding an address in use is an error for the caller rather than a panic
+        let config = ServerConfig {
+            port: usize::from(first_addr.port()),
+            ..Default::default()
+        };
+        let error = bind(test_application_with(config)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+
+        // Connections made before serving starts wait in the backlog
+        let stream = connect_to(first_addr);
+        let shutdown = first.shutdown_handle();
+        let server = thread::spawn(move || first.serve());
+        let response = exchange(stream, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight_requests() {
+        let server = bind(test_application_with(ephemeral_config())).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        let mut idle = connect_to(address);
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut idle).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut busy = connect_to(address);
+        busy.write_all(b"GET /sleep/300 HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+
+        // The in-flight request is answered and the connection closed after it
+        let response = read_response(&mut busy);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(read_response(&mut idle), "");
+
+        server.join().unwrap();
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_finishes_request_being_received() {
+        let server = bind(test_application_with(ephemeral_config())).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        // Part of the request has arrived, so the connection isn't idle
+        let mut stream = connect_to(address);
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"Host: localhost\r\n\r\n").unwrap();
+
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_closes_queued_connections_at_deadline() {
+        let config = ServerConfig {
+            workers: 1,
+            shutdown_timeout: Duration::from_millis(100),
+            ..ephemeral_config()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        // The only worker is busy past the deadline, the second connection waits in the queue
+        let mut busy = connect_to(address);
+        busy.write_all(b"GET /sleep/500 HTTP/1.1\r\n\r\n").unwrap();
+        let mut queued = connect_to(address);
+        queued.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+
+        server.join().unwrap();
+        let mut buffer = [0; 1024];
+        assert_eq!(queued.read(&mut buffer).unwrap_or(0), 0);
+        assert_eq!(busy.read(&mut buffer).unwrap_or(0), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_shutdown_closes_connections_at_deadline() {
+        use tokio::io::AsyncReadExt;
+
+        let config = ServerConfig {
+            shutdown_timeout: Duration::from_millis(100),
+            ..ephemeral_config()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.serve_async());
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /sleep/10000 HTTP/1.1\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.shutdown();
+
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "");
+    }
+
+    #[test]
+    fn test_connection_limit_rejects_with_retry_after() {
+        let config = ServerConfig {
+            max_connections: Some(1),
+            overflow: OverflowPolicy::Reject,
+            retry_after: Duration::from_secs(7),
+            ..ephemeral_config()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        // A persistent connection holds the only slot
+        let mut first = connect_to(address);
+        first.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut first).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = exchange(connect_to(address), b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 7\r\n"));
+
+        // The slot is free again once the worker is done with the connection
+        first.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        read_response(&mut first);
+        assert_eq!(read_response(&mut first), "");
+        thread::sleep(Duration::from_millis(50));
+        let response = exchange(connect_to(address), b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connection_limit_queues_connections() {
+        let config = ServerConfig {
+            max_connections: Some(1),
+            ..ephemeral_config()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.serve_async());
+
+        let response = tokio::task::spawn_blocking(move || {
+            let mut first = connect_to(address);
+            first.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            read_response(&mut first);
+
+            // The second connection isn't served while the first holds the only slot
+            let mut second = connect_to(address);
+            second.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            second.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            let mut buffer = [0; 64];
+            assert!(second.read(&mut buffer).is_err());
+
+            drop(first);
+            second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut response = String::new();
+            second.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.shutdown();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_request_limit_held_until_response_is_written() {
+        let config = ServerConfig {
+            max_requests: Some(1),
+            overflow: OverflowPolicy::Reject,
+            retry_after: Duration::from_millis(1500),
+            ..ephemeral_config()
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application_with(config).with_middleware(recorder("outer", &log));
+        let server = bind(application).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        // The handler is done, its response is still being written
+        let mut busy = connect_to(address);
+        busy.write_all(b"GET /large HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let rejected = exchange(connect_to(address), b"GET / HTTP/1.1\r\n\r\n");
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        // Partial seconds are rounded up
+        assert!(rejected.contains("Retry-After: 2\r\n"));
+        // The middleware sees requests turned away as well
+        assert!(rejected.contains("X-Middleware: outer\r\n"));
+        assert!(log.lock().unwrap().contains(&String::from("outer error")));
+
+        let mut response = Vec::new();
+        busy.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        let response = exchange(connect_to(address), b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_request_limit_rejects_with_retry_after() {
+        let config = ServerConfig {
+            max_requests: Some(1),
+            overflow: OverflowPolicy::Reject,
+            ..ephemeral_config()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.serve_async());
+
+        let (busy, rejected) = tokio::task::spawn_blocking(move || {
+            let mut busy = connect_to(address);
+            busy.write_all(b"GET /sleep/300 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let rejected = exchange(connect_to(address), b"GET / HTTP/1.1\r\n\r\n");
+            let mut response = String::new();
+            busy.read_to_string(&mut response).unwrap();
+            (response, rejected)
+        })
+        .await
+        .unwrap();
+        assert!(busy.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(rejected.contains("Retry-After: 1\r\n"));
+
+        shutdown.shutdown();
+        server.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("agire-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serves_multiple_listeners() {
+        use std::os::unix::net::UnixStream;
+
+        let path = socket_path("blocking");
+        let config = ServerConfig {
+            listeners: vec![
+                Listen::tcp("127.0.0.1:0"),
+                Listen::tcp("[::1]:0"),
+                Listen::unix_with_mode(&path, 0o660),
+            ],
+            ..Default::default()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let addresses = server.local_addrs();
+        assert!(addresses[1].as_tcp().unwrap().is_ipv6());
+        assert_eq!(addresses[2], LocalAddr::Unix(path.clone()));
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        let request = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = [
+            exchange(connect_to(addresses[0].as_tcp().unwrap()), request),
+            exchange(connect_to(addresses[1].as_tcp().unwrap()), request),
+            exchange(UnixStream::connect(&path).unwrap(), request),
+        ];
+        for response in responses {
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+
+        shutdown.shutdown();
+        server.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_serves_multiple_listeners() {
+        use std::os::unix::net::UnixStream;
+
+        let path = socket_path("async");
+        let config = ServerConfig {
+            listeners: vec![Listen::tcp("127.0.0.1:0"), Listen::unix(&path)],
+            ..Default::default()
+        };
+        let server = bind(test_application_with(config)).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.serve_async());
+
+        let unix_path = path.clone();
+        let responses = tokio::task::spawn_blocking(move || {
+            let request = b"GET /async HTTP/1.1\r\nConnection: close\r\n\r\n";
+            [
+                exchange(connect_to(address), request),
+                exchange(UnixStream::connect(&unix_path).unwrap(), request),
+            ]
+        })
+        .await
+        .unwrap();
+        for response in responses {
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("\r\n\r\nasync"));
+        }
+
+        shutdown.shutdown();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    /// Send a request over TLS and read until the server closes the session
+    fn tls_request(address: SocketAddr, certificate: &TestCertificate, request: &[u8]) -> (String, Option<Vec<u8>>) {
+        let connection = tls::tests::client(certificate, "localhost");
+        let mut stream = rustls::StreamOwned::new(connection, connect_to(address));
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let alpn = stream.conn.alpn_protocol().map(|i| i.to_vec());
+        (response, alpn)
+    }
+
+    fn tls_config(certificate: &TestCertificate) -> ServerConfig {
+        ServerConfig {
+            tls: Some(TlsConfig::new(&certificate.cert_path, &certificate.key_path)),
+            ..ephemeral_config()
+        }
+    }
+
+    #[test]
+    fn test_serves_https() {
+        let certificate = tls::tests::self_signed("localhost");
+        let server = bind(test_application_with(tls_config(&certificate))).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        let (response, alpn) = tls_request(address, &certificate, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok"));
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+
+        // Plaintext requests are not answered
+        let mut stream = connect_to(address);
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(!String::from_utf8_lossy(&response).contains("200 OK"));
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_raw_middleware_sees_decrypted_bytes() {
+        let certificate = tls::tests::self_signed("localhost");
+        let counter = ByteCounter::default();
+        let application = test_application_with(tls_config(&certificate)).with_raw_middleware(counter.clone());
+        let server = bind(application).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        // A failed handshake never reaches the raw middleware, the client only gets a TLS alert
+        let response = exchange(connect_to(address), b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!response.starts_with("HTTP/"));
+
+        let stream = connect_to(address);
+        let peer_addr = stream.local_addr().ok();
+        let connection = tls::tests::client(&certificate, "localhost");
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        let request = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(counter.read.load(Ordering::Relaxed), request.len());
+        assert_eq!(counter.written.load(Ordering::Relaxed), response.len());
+
+        tls_request(address, &certificate, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let connections = counter.connections.lock().unwrap().clone();
+        assert_eq!(connections.iter().map(|i| i.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(connections[0].peer_addr, peer_addr);
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_serves_https() {
+        let certificate = tls::tests::self_signed("localhost");
+        let counter = ByteCounter::default();
+        let application = test_application_with(tls_config(&certificate)).with_raw_middleware(counter.clone());
+        let server = bind(application).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.serve_async());
+
+        let request = b"GET /async HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let (response, alpn) = tokio::task::spawn_blocking(move || tls_request(address, &certificate, request))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nasync\
+             HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+        // The raw middleware sees the decrypted requests and responses
+        assert_eq!(counter.read.load(Ordering::Relaxed), request.len());
+        assert_eq!(counter.written.load(Ordering::Relaxed), response.len());
+
+        shutdown.shutdown();
+        server.await.unwrap();
+    }
+
+    /// Middleware recording when it runs, and tagging responses on their way out
+    pub(crate) struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RequestMiddleware<HttpRequest, HttpResponse> for Recorder {
+        fn on_request(
+            &self,
+            ctx: RequestContext<HttpRequest, HttpResponse>,
+        ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+            self.log.lock().unwrap().push(format!("{} request", self.name));
+            Ok(ctx)
+        }
+
+        fn on_response(
+            &self,
+            mut ctx: RequestContext<HttpRequest, HttpResponse>,
+        ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+            self.log.lock().unwrap().push(format!("{} response", self.name));
+            let mut response = Response::clone(ctx.get_response());
+            response.append_header("X-Middleware", self.name);
+            ctx.set_response(response);
+            Ok(ctx)
+        }
+
+        fn on_error(&self, _: &HttpRequest, error: ServerError) -> ServerError {
+            self.log.lock().unwrap().push(format!("{} error", self.name));
+            error
+        }
+    }
+
+    /// Rejects requests without an Authorization header
+    pub(crate) struct RequireAuth;
+
+    impl RequestMiddleware<HttpRequest, HttpResponse> for RequireAuth {
+        fn on_request(
+            &self,
+            ctx: RequestContext<HttpRequest, HttpResponse>,
+        ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+            if ctx.get_request().get_header("Authorization").is_none() {
+                return Err(StdServerError::Unauthorized.to_error());
+            }
+            Ok(ctx)
+        }
+    }
+
+    /// Answers every request itself, as a cache would
+    struct Cached;
+
+    impl RequestMiddleware<HttpRequest, HttpResponse> for Cached {
+        fn on_request(
+            &self,
+            mut ctx: RequestContext<HttpRequest, HttpResponse>,
+        ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+            let mut response = HttpResponse::new();
+            response.set_status_code(200);
+            response.set_body(String::from("cached"));
+            ctx.finish(response);
+            Ok(ctx)
+        }
+    }
+
+    /// Hides what exists from clients, every error becomes a 404
+    struct MaskErrors;
+
+    impl RequestMiddleware<HttpRequest, HttpResponse> for MaskErrors {
+        fn on_error(&self, _: &HttpRequest, _: ServerError) -> ServerError {
+            StdServerError::NotFound.to_error()
+        }
+    }
+
+    pub(crate) fn recorder(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Recorder {
+        Recorder {
+            name,
+            log: Arc::clone(log),
+        }
+    }
+
+    #[test]
+    fn test_middleware_runs_in_onion_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(recorder("inner", &log));
+
+        let ctx = application.handle(read_message(b"GET / HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["outer request", "inner request", "inner response", "outer response"]
+        );
+        let response = ctx.get_response();
+        assert_eq!(response.get_status_code(), Some(200));
+        assert_eq!(response.get_headers().get_all("X-Middleware"), vec!["inner", "outer"]);
+    }
+
+    #[test]
+    fn test_middleware_error_stops_request() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(RequireAuth)
+            .with_middleware(recorder("inner", &log));
+
+        // The error is answered through the middleware already entered
+        let ctx = application.handle(read_message(b"GET / HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["outer request", "outer error", "outer response"]);
+        let response = ctx.get_response();
+        assert_eq!(response.get_status_code(), Some(401));
+        assert_eq!(response.get_header("Connection"), Some(String::from("close")));
+        assert_eq!(response.get_headers().get_all("X-Middleware"), vec!["outer"]);
+
+        log.lock().unwrap().clear();
+        let ctx = application
+            .handle(read_message(b"GET / HTTP/1.1\r\nAuthorization: token\r\n\r\n"))
+            .unwrap();
+        assert_eq!(ctx.get_response().get_status_code(), Some(200));
+        assert_eq!(log.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_middleware_finishes_request_early() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(Cached)
+            .with_middleware(recorder("inner", &log));
+
+        let ctx = application.handle(read_message(b"GET /missing HTTP/1.1\r\n\r\n")).unwrap();
+        // The router and the inner middleware are skipped
+        assert_eq!(*log.lock().unwrap(), vec!["outer request", "outer response"]);
+        let response = ctx.get_response();
+        assert_eq!(response.get_body().text(), Some(String::from("cached")));
+        assert_eq!(response.get_headers().get_all("X-Middleware"), vec!["outer"]);
+    }
+
+    #[test]
+    fn test_route_errors_go_through_middleware() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(MaskErrors)
+            .with_middleware(recorder("inner", &log));
+
+        let ctx = application.handle(read_message(b"POST / HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "outer request",
+                "inner request",
+                "inner error",
+                "outer error",
+                "inner response",
+                "outer response",
+            ]
+        );
+        // The 405 was rewritten, so its Allow header is gone
+        let response = ctx.get_response();
+        assert_eq!(response.get_status_code(), Some(404));
+        assert_eq!(response.get_header("Allow"), None);
+        assert_eq!(response.get_headers().get_all("X-Middleware"), vec!["inner", "outer"]);
+
+        // Without middleware the error is returned to be sent as it is
+        let error = test_application().handle(read_message(b"POST / HTTP/1.1\r\n\r\n")).err().unwrap();
+        assert_eq!(error.get_status_code(), 405);
+    }
+
+    #[test]
+    fn test_errors_outside_chain_go_through_middleware() {
+        // A request that can't be parsed is answered through all of the middleware
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(MaskErrors);
+        let ctx = application.handle(read_message(b"GET / HTTP/2.0\r\n\r\n")).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["outer error", "outer response"]);
+        assert_eq!(ctx.get_response().get_status_code(), Some(404));
+
+        // So is a request the reader rejects
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut stream = connect_application(test_application().with_middleware(recorder("outer", &log)));
+        stream.write_all(b"GET / HTTP/1.0\r\nHost localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+        assert!(response.contains("X-Middleware: outer\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(*log.lock().unwrap(), vec!["outer error", "outer response"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_middleware_wraps_async_routes() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let application = test_application()
+            .with_middleware(recorder("outer", &log))
+            .with_middleware(recorder("inner", &log));
+
+        let ctx = application.handle_async(read_message(b"GET /async HTTP/1.1\r\n\r\n")).await.unwrap();
+        assert_eq!(log.lock().unwrap().len(), 4);
+        assert_eq!(
+            ctx.get_response().get_headers().get_all("X-Middleware"),
+            vec!["inner", "outer"]
+        );
+    }
+
+    #[test]
+    fn test_raw_middleware_counts_connection_bytes() {
+        let counter = ByteCounter::default();
+        let application = test_application_with(ephemeral_config()).with_raw_middleware(counter.clone());
+        let server = bind(application).unwrap();
+        let address = tcp_addr(&server);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve());
+
+        let request = b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = exchange(connect_to(address), request);
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert_eq!(counter.read.load(Ordering::Relaxed), request.len());
+        assert_eq!(counter.written.load(Ordering::Relaxed), response.len());
+
+        shutdown.shutdown();
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_raw_middleware_counts_connection_bytes() {
+        use tokio::io::AsyncReadExt;
+
+        let counter = ByteCounter::default();
+        let application = test_application().with_raw_middleware(counter.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            application.handle_connection(AsyncStream::Tcp(stream)).await;
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = b"GET /async HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(counter.read.load(Ordering::Relaxed), request.len());
+        assert_eq!(counter.written.load(Ordering::Relaxed), response.len());
+    }
+
+    fn slow_client_config() -> ServerConfig {
+        ServerConfig {
+            keep_alive_timeout: Duration::from_millis(200),
+            header_read_timeout: Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_errors_answered_in_request_version() {
+        let mut stream = connect();
+        stream.write_all(b"GET /missing HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        let mut stream = connect();
+        stream.write_all(b"GET / HTTP/1.0\r\nHost localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.0 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_slow_headers_time_out() {
+        let mut stream = connect_with(slow_client_config());
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: local").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(read_response(&mut stream), "");
+
+        // A connection that never sends anything is closed without a response
+        let mut stream = connect_with(slow_client_config());
+        assert_eq!(read_response(&mut stream), "");
+    }
+
+    #[tokio::test]
+    async fn test_async_slow_body_times_out() {
+        use tokio::io::AsyncReadExt;
+
+        let config = ServerConfig {
+            body_read_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            test_application_with(config).handle_connection(AsyncStream::Tcp(stream)).await;
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+}
@@ -1,6 +1,9 @@
-use crate::http::types::HttpMethod;
+use crate::http::body::Body;
+use crate::http::headers::HeaderMap;
+use crate::http::types::{HttpMethod, HttpVersion};
 use crate::server::traits::{Request, Response};
 
+use bytes::Bytes;
 use std::collections::HashMap;
 
 use log;
@@ -8,6 +11,13 @@ use log;
 pub struct RequestContext<T: Request, R: Response> {
     request: T,
     response: R,
+    finished: bool,
+}
+
+impl<T: Request, R: Response> Default for RequestContext<T, R> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Request, R: Response> RequestContext<T, R> {
@@ -15,6 +25,7 @@ impl<T: Request, R: Response> RequestContext<T, R> {
         Self {
             request: T::new(),
             response: R::new(),
+            finished: false,
         }
     }
 
@@ -33,25 +44,42 @@ impl<T: Request, R: Response> RequestContext<T, R> {
     pub fn set_response(&mut self, response: R) {
         self.response = response;
     }
+
+    /// Answer the request with `response` from request middleware.
+    /// The remaining request middleware and the route handler are skipped,
+    /// the response still goes through the response middleware already entered.
+    pub fn finish(&mut self, response: R) {
+        self.response = response;
+        self.finished = true;
+    }
+
+    /// Whether the response was set with `finish`
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
 }
 
 pub struct HttpRequest {
     method: HttpMethod,
+    version: HttpVersion,
     path: String,
     path_params: HashMap<String, String>,
-    query_params: HashMap<String, String>,
-    headers: HashMap<String, String>,
-    body: String,
+    query_params: Vec<(String, String)>,
+    headers: HeaderMap,
+    trailers: HeaderMap,
+    body: Bytes,
 }
 
 impl Request for HttpRequest {
     fn clone(&self) -> Self {
         Self {
             method: self.method.clone(),
+            version: self.version,
             path: self.path.clone(),
             path_params: self.path_params.clone(),
             query_params: self.query_params.clone(),
             headers: self.headers.clone(),
+            trailers: self.trailers.clone(),
             body: self.body.clone(),
         }
     }
@@ -59,11 +87,13 @@ impl Request for HttpRequest {
     fn new() -> Self {
         Self {
             method: HttpMethod::Unset,
+            version: HttpVersion::V1_1,
             path: String::new(),
             path_params: HashMap::new(),
-            query_params: HashMap::new(),
-            headers: HashMap::new(),
-            body: String::new(),
+            query_params: Vec::new(),
+            headers: HeaderMap::new(),
+            trailers: HeaderMap::new(),
+            body: Bytes::new(),
         }
     }
 
@@ -75,8 +105,16 @@ impl Request for HttpRequest {
         self.method = method;
     }
 
+    fn get_version(&self) -> HttpVersion {
+        self.version
+    }
+
+    fn set_version(&mut self, version: HttpVersion) {
+        self.version = version;
+    }
+
     fn get_path(&self) -> String {
-        return self.path.clone();
+        self.path.clone()
     }
 
     fn set_path(&mut self, path: String) {
@@ -84,10 +122,7 @@ impl Request for HttpRequest {
     }
 
     fn get_path_param(&self, name: &str) -> Option<String> {
-        match self.path_params.get(name) {
-            Some(v) => Some(v.clone()),
-            None => None,
-        }
+        self.path_params.get(name).cloned()
     }
 
     fn set_path_param(&mut self, name: &str, value: &str) {
@@ -96,29 +131,59 @@ impl Request for HttpRequest {
     }
 
     fn get_query_param(&self, name: &str) -> Option<String> {
-        match self.query_params.get(name) {
-            Some(v) => Some(v.clone()),
-            None => None,
-        }
+        self.query_params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn get_query_params(&self, name: &str) -> Vec<String> {
+        self.query_params
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    fn add_query_param(&mut self, name: &str, value: &str) {
+        self.query_params
+            .push((String::from(name), String::from(value)));
     }
 
     fn get_header(&self, name: &str) -> Option<String> {
-        match self.headers.get(name) {
-            Some(v) => Some(v.clone()),
-            None => None,
-        }
+        self.headers.get(name).map(String::from)
     }
 
-    fn get_body(&self) -> String {
+    fn get_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn get_body(&self) -> Bytes {
         self.body.clone()
     }
 
     fn set_header(&mut self, name: &str, value: &str) {
         log::debug!("Setting header: {}: {}", name, value);
-        self.headers.insert(String::from(name), String::from(value));
+        self.headers.insert(name, value);
+    }
+
+    fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.append(name, value);
+    }
+
+    fn get_trailer(&self, name: &str) -> Option<String> {
+        self.trailers.get(name).map(String::from)
+    }
+
+    fn get_trailers(&self) -> &HeaderMap {
+        &self.trailers
     }
 
-    fn set_body(&mut self, body: String) {
+    fn append_trailer(&mut self, name: &str, value: &str) {
+        self.trailers.append(name, value);
+    }
+
+    fn set_body(&mut self, body: Bytes) {
         self.body = body;
     }
 }
@@ -126,14 +191,14 @@ impl Request for HttpRequest {
 #[derive(Clone)]
 pub struct HttpResponse {
     status_code: Option<usize>,
-    headers: HashMap<String, String>,
-    body: String,
+    headers: HeaderMap,
+    body: Body,
 }
 
 impl Response for HttpResponse {
     fn clone(&self) -> Self {
         Self {
-            status_code: self.status_code.clone(),
+            status_code: self.status_code,
             headers: self.headers.clone(),
             body: self.body.clone(),
         }
@@ -141,13 +206,13 @@ impl Response for HttpResponse {
     fn new() -> Self {
         Self {
             status_code: None,
-            headers: HashMap::new(),
-            body: String::new(),
+            headers: HeaderMap::new(),
+            body: Body::empty(),
         }
     }
 
     fn get_status_code(&self) -> Option<usize> {
-        self.status_code.clone()
+        self.status_code
     }
 
     fn set_status_code(&mut self, code: usize) {
@@ -155,31 +220,32 @@ impl Response for HttpResponse {
     }
 
     fn get_header(&self, name: &str) -> Option<String> {
-        match self.headers.get(name) {
-            Some(v) => Some(v.clone()),
-            None => None,
-        }
+        self.headers.get(name).map(String::from)
+    }
+
+    fn get_headers(&self) -> &HeaderMap {
+        &self.headers
     }
 
     fn set_header(&mut self, name: &str, value: &str) {
-        self.headers.insert(String::from(name), String::from(value));
+        self.headers.insert(name, value);
     }
 
-    fn get_headers(&self) -> &HashMap<String, String> {
-        &self.headers
+    fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.append(name, value);
     }
 
-    fn get_body(&self) -> String {
-        self.body.clone()
+    fn get_body(&self) -> &Body {
+        &self.body
     }
 
-    fn set_body(&mut self, body: String) {
-        self.body = body;
+    fn set_body<B: Into<Body>>(&mut self, body: B) {
+        self.body = body.into();
     }
 }
 
 impl HttpResponse {
-    fn plaintext_response(body: String) -> HttpResponse {
+    pub fn plaintext_response(body: String) -> HttpResponse {
         let mut response = HttpResponse::new();
         response.set_header("Content-Type", "text/plain");
         response.set_body(body);
@@ -1,17 +1,33 @@
+use crate::http::error::HttpError;
 use crate::server::traits::Error;
 
+#[derive(Debug)]
 pub struct ServerError {
     status_code: usize,
     detail: String,
+    headers: Vec<(String, String)>,
 }
 
 impl ServerError {
     pub fn new(status_code: usize, detail: String) -> Self {
         Self {
-            status_code: status_code,
-            detail: detail,
+            status_code,
+            detail,
+            headers: Vec::new(),
         }
     }
+
+    /// Add a header to send with the error response
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+}
+
+impl From<HttpError> for ServerError {
+    fn from(error: HttpError) -> Self {
+        Self::new(error.code, error.detail)
+    }
 }
 
 impl Error for ServerError {
@@ -21,6 +37,9 @@ impl Error for ServerError {
     fn get_detail(&self) -> String {
         self.detail.clone()
     }
+    fn get_headers(&self) -> Vec<(String, String)> {
+        self.headers.clone()
+    }
 }
 
 pub enum StdServerError {
@@ -32,16 +51,21 @@ pub enum StdServerError {
     MethodNotAllowed,
     // NotAcceptable,
     // ProxyAuthenticationRequired,
-    // RequestTimeout,
+    RequestTimeout,
     // Conflict,
     // Gone,
     // ...
+    ContentTooLarge,
+    UriTooLong,
+    // ...
     UnprocessableContent,
     // ...
+    RequestHeaderFieldsTooLarge,
+    // ...
     InternalServerError,
     NotImplemented,
     // BadGateway,
-    // ServiceUnavailable,
+    ServiceUnavailable,
     // GatewayTimeout,
     HttpVersionNotSupported,
     // ...
@@ -51,57 +75,34 @@ impl StdServerError {
     pub fn to_error(&self) -> ServerError {
         match self {
             // 400s
-            StdServerError::BadRequest => ServerError {
-                status_code: 400,
-                detail: String::from("Bad Request"),
-            },
-            StdServerError::Unauthorized => ServerError {
-                status_code: 401,
-                detail: String::from("Unauthorized"),
-            },
-            StdServerError::PaymentRequired => ServerError {
-                status_code: 402,
-                detail: String::from("Payment Required"),
-            },
-            StdServerError::Forbidden => ServerError {
-                status_code: 403,
-                detail: String::from("Forbidden"),
-            },
-            StdServerError::NotFound => ServerError {
-                status_code: 404,
-                detail: String::from("Not Found"),
-            },
-            StdServerError::MethodNotAllowed => ServerError {
-                status_code: 405,
-                detail: String::from("Method Not Allowed"),
-            },
+            StdServerError::BadRequest => ServerError::new(400, String::from("Bad Request")),
+            StdServerError::Unauthorized => ServerError::new(401, String::from("Unauthorized")),
+            StdServerError::PaymentRequired => ServerError::new(402, String::from("Payment Required")),
+            StdServerError::Forbidden => ServerError::new(403, String::from("Forbidden")),
+            StdServerError::NotFound => ServerError::new(404, String::from("Not Found")),
+            StdServerError::MethodNotAllowed => ServerError::new(405, String::from("Method Not Allowed")),
             // StdServerError::NotAcceptable => ServerError{status_code:406, detail: String::from("Not Acceptable"),
             // StdServerError::ProxyAuthenticationRequired => ServerError{status_code:407, detail: String::from("Proxy Authentication Required"),
-            // StdServerError::RequestTimeout => ServerError{status_code:408, detail: String::from("Request Timeout"),
+            StdServerError::RequestTimeout => ServerError::new(408, String::from("Request Timeout")),
             // StdServerError::Conflict => ServerError{status_code:409, detail: String::from("Conflict"),
             // StdServerError::Gone => ServerError{status_code: 410, detail: String::from("Gone"),
             // StdServerError::...
-            StdServerError::UnprocessableContent => ServerError {
-                status_code: 422,
-                detail: String::from("Unprocessable Content"),
-            },
+            StdServerError::ContentTooLarge => ServerError::new(413, String::from("Content Too Large")),
+            StdServerError::UriTooLong => ServerError::new(414, String::from("URI Too Long")),
+            // StdServerError::...
+            StdServerError::UnprocessableContent => ServerError::new(422, String::from("Unprocessable Content")),
+            // StdServerError::...
+            StdServerError::RequestHeaderFieldsTooLarge => {
+                ServerError::new(431, String::from("Request Header Fields Too Large"))
+            }
             // StdServerError::...
             // 500s
-            StdServerError::InternalServerError => ServerError {
-                status_code: 500,
-                detail: String::from("Unprocessable Content"),
-            },
-            StdServerError::NotImplemented => ServerError {
-                status_code: 501,
-                detail: String::from("Internal Server Error"),
-            },
+            StdServerError::InternalServerError => ServerError::new(500, String::from("Internal Server Error")),
+            StdServerError::NotImplemented => ServerError::new(501, String::from("Not Implemented")),
             // StdServerError::BadGateway => ServerError{status_code: 502, detail: String::from("Bad Gateway")},
-            // StdServerError::ServiceUnavailable => ServerError{status_code: 503, detail: String::from("Service Unavailable")},
+            StdServerError::ServiceUnavailable => ServerError::new(503, String::from("Service Unavailable")),
             // StdServerError::GatewayTimeout => ServerError{status_code: 504, detail: String::from("Gateway Timeout")},
-            StdServerError::HttpVersionNotSupported => ServerError {
-                status_code: 505,
-                detail: String::from("Http Version Not Supported"),
-            },
+            StdServerError::HttpVersionNotSupported => ServerError::new(505, String::from("HTTP Version Not Supported")),
             // StdServerError::...
         }
     }
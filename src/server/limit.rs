@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop accepting until there is room, new connections wait in the listen backlog
+    /// and requests wait for one being handled to finish
+    Wait,
+    /// Answer `503 Service Unavailable` with `Retry-After` and close the connection
+    Reject,
+}
+
+/// Caps how many connections, or requests, are served at once.
+/// Shared by the blocking and async servers, blocking code waits on it with `runtime::block_on`.
+pub(crate) struct Limit {
+    semaphore: Option<Arc<Semaphore>>,
+    policy: OverflowPolicy,
+}
+
+/// Slot taken from a `Limit`, given back when dropped
+pub(crate) struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Limit {
+    /// `None` places no limit
+    pub(crate) fn new(max: Option<usize>, policy: OverflowPolicy) -> Self {
+        Self {
+            semaphore: max.map(|i| Arc::new(Semaphore::new(i))),
+            policy,
+        }
+    }
+
+    /// Take a slot, waiting for one to free up when the policy is `Wait`.
+    /// Returns `None` when the limit is reached and the policy is `Reject`.
+    pub(crate) async fn acquire(&self) -> Option<Permit> {
+        let semaphore = match &self.semaphore {
+            Some(val) => Arc::clone(val),
+            None => return Some(Permit { _permit: None }),
+        };
+        // The semaphore is never closed, so taking a slot only fails when none are free
+        let permit = match self.policy {
+            OverflowPolicy::Wait => semaphore.acquire_owned().await.ok()?,
+            OverflowPolicy::Reject => semaphore.try_acquire_owned().ok()?,
+        };
+        Some(Permit {
+            _permit: Some(permit),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::runtime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reject_over_limit() {
+        let limit = Limit::new(Some(1), OverflowPolicy::Reject);
+        let permit = runtime::block_on(limit.acquire());
+        assert!(permit.is_some());
+        assert!(runtime::block_on(limit.acquire()).is_none());
+
+        drop(permit);
+        assert!(runtime::block_on(limit.acquire()).is_some());
+
+        let unlimited = Limit::new(None, OverflowPolicy::Reject);
+        let permits = (0..100).map(|_| runtime::block_on(unlimited.acquire())).collect::<Vec<_>>();
+        assert!(permits.iter().all(|i| i.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_free_slot() {
+        let limit = Limit::new(Some(1), OverflowPolicy::Wait);
+        let permit = limit.acquire().await;
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limit.acquire()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        assert!(limit.acquire().await.is_some());
+    }
+}
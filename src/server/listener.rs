@@ -0,0 +1,687 @@
+use crate::server::reader::ReadTimeout;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// First file descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Most descriptors taken from `LISTEN_FDS`, a larger count is a broken environment rather than real sockets
+#[cfg(unix)]
+const SD_LISTEN_FDS_MAX: RawFd = 1024;
+
+/// Where the server listens for connections
+#[derive(Debug)]
+pub enum Listen {
+    /// TCP socket address such as `127.0.0.1:4221` or `[::1]:4221`
+    Tcp(String),
+    /// Unix domain socket, the file permissions are set to `mode` when given
+    #[cfg(unix)]
+    Unix { path: PathBuf, mode: Option<u32> },
+    /// Listening TCP or Unix socket already opened by a parent process, such as systemd or a supervisor.
+    /// The server owns the descriptor and closes it when it stops.
+    #[cfg(unix)]
+    Fd(OwnedFd),
+}
+
+impl Listen {
+    pub fn tcp(address: &str) -> Self {
+        Listen::Tcp(address.to_string())
+    }
+
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Listen::Unix {
+            path: path.into(),
+            mode: None,
+        }
+    }
+
+    /// Unix socket with its file permissions set to `mode`, such as `0o660` to only allow the owner and group
+    #[cfg(unix)]
+    pub fn unix_with_mode<P: Into<PathBuf>>(path: P, mode: u32) -> Self {
+        Listen::Unix {
+            path: path.into(),
+            mode: Some(mode),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Listen::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Listen::Fd(fd) => write!(f, "fd:{}", fd.as_raw_fd()),
+        }
+    }
+}
+
+/// Address a listener is bound to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl LocalAddr {
+    /// Socket address of a TCP listener
+    pub fn as_tcp(&self) -> Option<SocketAddr> {
+        match self {
+            LocalAddr::Tcp(address) => Some(*address),
+            #[cfg(unix)]
+            LocalAddr::Unix(_) => None,
+        }
+    }
+
+    /// Open a connection and drop it straight away, used to wake a blocking acceptor
+    pub(crate) fn poke(&self) {
+        match self {
+            LocalAddr::Tcp(address) => {
+                let _ = TcpStream::connect(address);
+            }
+            #[cfg(unix)]
+            LocalAddr::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalAddr::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            LocalAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Removes the socket file of a Unix listener once the listener is closed
+#[cfg(unix)]
+pub(crate) struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Sockets passed with systemd socket activation, empty when the process wasn't started that way.
+/// The variables are left in place, calling this twice hands out the same descriptors twice.
+/// Callers should call it once and then remove `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+/// while the process is still single-threaded, so they aren't passed on to child processes.
+#[cfg(unix)]
+pub fn systemd_listeners() -> io::Result<Vec<Listen>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let fds = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    inherited_fds(fds)
+}
+
+/// Take ownership of the passed descriptors, each one has to be open.
+/// None is taken when one isn't, so a descriptor we don't own is never closed.
+#[cfg(unix)]
+fn inherited_fds(fds: Vec<RawFd>) -> io::Result<Vec<Listen>> {
+    for &fd in &fds {
+        // Safety: F_GETFD only reads the descriptor flags and fails on a closed descriptor
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            let error = io::Error::last_os_error();
+            return Err(io::Error::new(error.kind(), format!("fd {} passed by systemd: {}", fd, error)));
+        }
+    }
+    // Safety: systemd passed these descriptors to this process, they are open and nothing else has claimed them
+    Ok(fds.into_iter().map(|fd| Listen::Fd(unsafe { OwnedFd::from_raw_fd(fd) })).collect())
+}
+
+/// Descriptors passed to process `pid` according to the `LISTEN_PID` and `LISTEN_FDS` values
+#[cfg(unix)]
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<Vec<RawFd>> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    // The variables may have been meant for a parent that didn't clear them
+    if pid.trim().parse::<u32>().ok() != Some(own_pid) {
+        return Ok(Vec::new());
+    }
+    let count = match fds.trim().parse::<RawFd>() {
+        Ok(val) if (0..=SD_LISTEN_FDS_MAX).contains(&val) => val,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid LISTEN_FDS value {:?}", fds),
+            ))
+        }
+    };
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect())
+}
+
+/// Bound listening socket of the blocking server
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// The socket file is only removed when the listener created it
+    #[cfg(unix)]
+    Unix(UnixListener, Option<SocketFile>),
+}
+
+impl Listener {
+    pub(crate) fn bind(listen: Listen) -> io::Result<Self> {
+        match listen {
+            Listen::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+            #[cfg(unix)]
+            Listen::Unix { path, mode } => {
+                remove_stale_socket(&path)?;
+                let listener = bind_unix(&path, mode)?;
+                Ok(Listener::Unix(listener, Some(SocketFile(path))))
+            }
+            #[cfg(unix)]
+            Listen::Fd(fd) => Listener::from_fd(fd),
+        }
+    }
+
+    /// Take over an inherited socket, it has to be a listening stream socket.
+    /// The descriptor is made blocking and closed on exec whatever the parent left it as.
+    #[cfg(unix)]
+    fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let raw = fd.as_raw_fd();
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} {}", raw, message));
+
+        set_cloexec(&fd)?;
+        if socket_option(&fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(invalid("is not a stream socket"));
+        }
+        if socket_option(&fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(invalid("is not listening"));
+        }
+        match socket_family(&fd)? {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = TcpListener::from(fd);
+                listener.set_nonblocking(false)?;
+                Ok(Listener::Tcp(listener))
+            }
+            libc::AF_UNIX => {
+                let listener = UnixListener::from(fd);
+                listener.set_nonblocking(false)?;
+                Ok(Listener::Unix(listener, None))
+            }
+            _ => Err(invalid("is not a TCP or Unix socket")),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<LocalAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(LocalAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener, file) => Ok(unix_addr(listener.local_addr()?.as_pathname(), file)),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    /// Hand the socket over to the tokio runtime, must be called from within it
+    pub(crate) fn into_async(self) -> io::Result<AsyncListener> {
+        match self {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, file) => {
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Unix(tokio::net::UnixListener::from_std(listener)?, file))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unix_addr(path: Option<&Path>, file: &Option<SocketFile>) -> LocalAddr {
+    // A socket bound with a mode reports the private path it was bound at before being linked into place
+    match (path, file) {
+        (_, Some(file)) => LocalAddr::Unix(file.0.clone()),
+        (Some(path), None) => LocalAddr::Unix(path.to_path_buf()),
+        // Unnamed and abstract sockets have no path to report
+        (None, None) => LocalAddr::Unix(PathBuf::new()),
+    }
+}
+
+/// Integer option of the socket at the `SOL_SOCKET` level
+#[cfg(unix)]
+fn socket_option(fd: &OwnedFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safety: `value` and `len` describe a buffer the size of an integer option
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Address family of the socket, such as `AF_INET` or `AF_UNIX`
+#[cfg(unix)]
+fn socket_family(fd: &OwnedFd) -> io::Result<libc::c_int> {
+    // Safety: an all zero sockaddr_storage is valid, and `len` is its size
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(address.ss_family as libc::c_int)
+}
+
+/// Keep the descriptor from leaking into processes the server runs
+#[cfg(unix)]
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // Safety: fcntl only reads and sets the descriptor flags of a descriptor we own
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Bind a Unix socket at `path` with its permissions already set to `mode` when anyone can reach it.
+/// It is bound inside a directory only the owner can enter, then linked into place,
+/// which fails rather than replace a file created at `path` in the meantime.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    let mode = match mode {
+        Some(val) => val,
+        None => return UnixListener::bind(path),
+    };
+    let name = match path.file_name() {
+        Some(val) => val.to_string_lossy(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a socket file path", path.display()),
+            ))
+        }
+    };
+    let dir = path.with_file_name(format!(
+        ".{}.{}.{}",
+        name,
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private = dir.join("socket");
+    let result = UnixListener::bind(&private).and_then(|listener| {
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+        std::fs::hard_link(&private, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&private);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+/// A socket file left behind by a server that is gone would make binding fail, it is removed
+/// when connecting to it is refused. Any other answer may mean a server is still behind it,
+/// the file is left alone and binding reports the conflict.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(val) if val.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+            Err(_) => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Accepted connection of the blocking server
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Address of the client, `None` over Unix sockets
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl ReadTimeout for &Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Bound listening socket of the async server
+pub(crate) enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, Option<SocketFile>),
+}
+
+impl AsyncListener {
+    pub(crate) fn local_addr(&self) -> io::Result<LocalAddr> {
+        match self {
+            AsyncListener::Tcp(listener) => Ok(LocalAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            AsyncListener::Unix(listener, file) => Ok(unix_addr(listener.local_addr()?.as_pathname(), file)),
+        }
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<AsyncStream> {
+        match self {
+            AsyncListener::Tcp(listener) => Ok(AsyncStream::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            AsyncListener::Unix(listener, _) => Ok(AsyncStream::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Accepted connection of the async server
+pub(crate) enum AsyncStream {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncStream {
+    /// Address of the client, `None` over Unix sockets
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            AsyncStream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_ipv6_literal() {
+        let listener = Listener::bind(Listen::tcp("[::1]:0")).unwrap();
+        match listener.local_addr().unwrap() {
+            LocalAddr::Tcp(address) => assert!(address.is_ipv6()),
+            other => panic!("Unexpected address {}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("agire-listener-{}.sock", std::process::id()));
+        let listener = Listener::bind(Listen::unix_with_mode(&path, 0o600)).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A socket with a server behind it is not taken over
+        assert!(Listener::bind(Listen::unix(&path)).is_err());
+
+        // The socket file is removed with the listener
+        drop(listener);
+        assert!(!path.exists());
+
+        // A stale socket file left behind is replaced
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(path.exists());
+        let listener = Listener::bind(Listen::unix(&path)).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), LocalAddr::Unix(path.clone()));
+        drop(listener);
+
+        // Other files are never replaced, and the private directory is cleaned up
+        std::fs::write(&path, "data").unwrap();
+        assert!(Listener::bind(Listen::unix_with_mode(&path, 0o600)).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        let prefix = format!(".{}.", path.file_name().unwrap().to_string_lossy());
+        let leftover = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .any(|i| i.unwrap().file_name().to_string_lossy().starts_with(&prefix));
+        assert!(!leftover);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(None, None, 42).unwrap(), vec![]);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), vec![]);
+        assert_eq!(
+            listen_fds(Some("42"), Some("2"), 42).unwrap(),
+            vec![3, 4]
+        );
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+        assert!(listen_fds(Some("42"), Some("-1"), 42).is_err());
+        assert_eq!(listen_fds(Some("42"), Some("1024"), 42).unwrap().len(), 1024);
+        assert!(listen_fds(Some("42"), Some("1025"), 42).is_err());
+        assert!(listen_fds(Some("42"), Some("2147483647"), 42).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inherited_fds_must_be_open() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let fd = std::os::unix::io::IntoRawFd::into_raw_fd(tcp);
+
+        // The open descriptor isn't taken either, it is still ours to close
+        let error = inherited_fds(vec![fd, RawFd::MAX]).err().unwrap();
+        assert!(error.to_string().starts_with(&format!("fd {} passed by systemd", RawFd::MAX)));
+        // Safety: the descriptor was released by the listener above and not taken by inherited_fds
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        let mut listeners = inherited_fds(vec![fd]).unwrap();
+        let listener = Listener::bind(listeners.remove(0)).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), LocalAddr::Tcp(address));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_inherited_fd() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let listener = Listener::bind(Listen::Fd(tcp.into())).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), LocalAddr::Tcp(address));
+        let client = TcpStream::connect(address).unwrap();
+        assert!(matches!(listener.accept().unwrap(), Stream::Tcp(_)));
+        drop(client);
+
+        let path = std::env::temp_dir().join(format!("agire-inherited-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        let listener = Listener::bind(Listen::Fd(unix.into())).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), LocalAddr::Unix(path.clone()));
+
+        // The socket file belongs to whoever opened the socket, it is left in place
+        drop(listener);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inherited_fd_is_blocking_and_closed_on_exec() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        tcp.set_nonblocking(true).unwrap();
+        let fd = OwnedFd::from(tcp);
+        // Safety: clearing a flag of a descriptor owned by the test
+        unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, 0) };
+
+        let listener = Listener::bind(Listen::Fd(fd)).unwrap();
+        let raw = match &listener {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(..) => panic!("Expected a TCP listener"),
+        };
+        let (status, flags) = unsafe { (libc::fcntl(raw, libc::F_GETFL), libc::fcntl(raw, libc::F_GETFD)) };
+        assert_eq!(status & libc::O_NONBLOCK, 0);
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inherited_fd_must_be_listening_stream() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = Listener::bind(Listen::Fd(udp.into())).err().unwrap();
+        assert!(error.to_string().ends_with("is not a stream socket"));
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        let error = Listener::bind(Listen::Fd(client.into())).err().unwrap();
+        assert!(error.to_string().ends_with("is not listening"));
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(Listener::bind(Listen::Fd(file.into())).is_err());
+    }
+}
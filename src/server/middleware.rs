@@ -0,0 +1,97 @@
+use crate::server::context::RequestContext;
+use crate::server::error::ServerError;
+use crate::server::parse;
+use crate::server::traits::{Request, RequestMiddleware, Response};
+
+use std::sync::Arc;
+
+/// Middleware a request has passed through on its way in, so the result can be passed back out.
+/// Application, group and route middleware share one chain, an error anywhere inside
+/// goes through `on_error` of every middleware entered before it is turned into a response
+/// for their `on_response`.
+pub(crate) struct Chain<T: Request, R: Response> {
+    /// Middleware whose `on_request` succeeded, outermost first
+    entered: Vec<Arc<dyn RequestMiddleware<T, R>>>,
+    /// Copy of the request as the outermost middleware passed it on,
+    /// an error response is built around it once the context is gone
+    request: Option<T>,
+}
+
+impl<T: Request, R: Response> Default for Chain<T, R> {
+    fn default() -> Self {
+        Self {
+            entered: Vec::new(),
+            request: None,
+        }
+    }
+}
+
+impl<T: Request, R: Response> Chain<T, R> {
+    /// Run `on_request` of each middleware in order, stopping at the first error or finished response.
+    /// Middleware returning an error or finishing the request isn't entered, it won't see the result.
+    pub(crate) fn enter(
+        &mut self,
+        middleware: &[Arc<dyn RequestMiddleware<T, R>>],
+        ctx: RequestContext<T, R>,
+    ) -> Result<RequestContext<T, R>, ServerError> {
+        let mut ctx = ctx;
+        for i in middleware.iter() {
+            ctx = i.on_request(ctx)?;
+            if ctx.is_finished() {
+                break;
+            }
+            // Nothing needs the request unless some middleware has been entered, one copy is enough
+            if self.request.is_none() {
+                self.request = Some(ctx.get_request().clone());
+            }
+            self.entered.push(Arc::clone(i));
+        }
+        Ok(ctx)
+    }
+
+    /// Pass an error raised before the middleware could run, such as a malformed request
+    /// or the server being at capacity, through all of it as if every middleware had been entered
+    pub(crate) fn reject(
+        middleware: &[Arc<dyn RequestMiddleware<T, R>>],
+        request: T,
+        error: ServerError,
+    ) -> Result<RequestContext<T, R>, ServerError> {
+        let chain = Chain {
+            entered: middleware.to_vec(),
+            request: Some(request),
+        };
+        chain.leave(Err(error))
+    }
+
+    /// Pass the result out through `on_response` of the middleware entered, innermost first.
+    /// Errors go through `on_error` of every middleware left and become a response for them,
+    /// they are only returned when no middleware is left to see them.
+    pub(crate) fn leave(
+        self,
+        result: Result<RequestContext<T, R>, ServerError>,
+    ) -> Result<RequestContext<T, R>, ServerError> {
+        let Chain { mut entered, mut request } = self;
+        let mut result = result;
+        loop {
+            result = match result {
+                Ok(ctx) => match entered.pop() {
+                    Some(middleware) => middleware.on_response(ctx),
+                    None => return Ok(ctx),
+                },
+                Err(e) if entered.is_empty() => return Err(e),
+                Err(e) => {
+                    let request = request.get_or_insert_with(T::new);
+                    let error = entered
+                        .iter()
+                        .rev()
+                        .fold(e, |error, middleware| middleware.on_error(request, error));
+                    let mut ctx = RequestContext::new();
+                    ctx.set_response(parse::error_into_response(&error));
+                    // Only errors need another copy, middleware further out may fail again
+                    ctx.set_request(request.clone());
+                    Ok(ctx)
+                }
+            };
+        }
+    }
+}
@@ -1,6 +1,14 @@
 pub mod application;
 pub mod context;
 pub mod error;
+pub(crate) mod limit;
+pub mod listener;
+pub(crate) mod middleware;
 pub mod parse;
+pub(crate) mod raw;
+pub mod reader;
 pub mod routing;
+pub mod runtime;
+pub mod shutdown;
+pub mod tls;
 pub mod traits;
@@ -1,95 +1,446 @@
+use crate::http::body::{Body, Chunks};
 use crate::http::http11;
-use crate::http::types::HttpMethod;
+use crate::http::types::{self, HttpMethod, HttpVersion};
+use crate::http::uri;
+use crate::server::context::{HttpRequest, HttpResponse};
 use crate::server::error::{ServerError, StdServerError};
+use crate::server::reader::RequestMessage;
 use crate::server::traits::{Error, Request, Response};
 
-use log::{debug, error, info, trace, warn};
+use bytes::Bytes;
+use log::debug;
+use std::io::{self, Write};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 
-/// Parse the incoming request bytes into a struct that implements Request
-pub fn parse_into_request<T: Request>(raw: String) -> Result<T, ServerError> {
+/// Build a struct that implements Request from a message read from a connection,
+/// the reader has already parsed the head and removed any transfer coding from the body
+pub fn parse_into_request<T: Request>(message: RequestMessage) -> Result<T, ServerError> {
     let mut request = T::new();
+    let rl = message.request_line;
 
-    // Parse Request Line
-    let rl = match http11::parse_request_line(&raw) {
-        Some(val) => val,
-        None => {
-            debug!("Failed to parse request line.");
-            debug!("{}", &raw);
-            return Err(StdServerError::BadRequest.to_error());
-        }
-    };
+    // Only HTTP/1.x messages can be read from this connection
+    match rl.version {
+        HttpVersion::V1_0 | HttpVersion::V1_1 => request.set_version(rl.version),
+        _ => return Err(StdServerError::HttpVersionNotSupported.to_error()),
+    }
 
     // Request Method
-    match HttpMethod::from_str(&rl.0) {
-        Ok(val) => request.set_method(val),
-        Err(_) => {
-            debug!("Failed to parse request method.");
-            debug!("{}", &raw);
-            return Err(StdServerError::BadRequest.to_error());
+    request.set_method(rl.method);
+
+    // Path + Query params
+    let (path, query) = uri::split_target(&rl.target);
+    request.set_path(String::from(path));
+    if let Some(query) = query {
+        for (name, value) in uri::parse_query(query) {
+            request.add_query_param(&name, &value);
         }
     }
 
-    // Path
-    let uri = rl.1;
-    // Path + Query params not currently supported
-    request.set_path(uri);
-
     // Headers
-    for (name, value) in http11::parse_headers(&raw) {
-        request.set_header(&name, &value);
+    for (name, value) in message.headers.iter() {
+        request.append_header(name, value);
     }
 
     // Body
-    match http11::parse_body(&raw) {
-        Some(body) => {
-            request.set_body(body);
-        },
-        None => {},
+    if http11::is_chunked(&message.headers)? && !rl.version.supports_chunked() {
+        debug!("Transfer-Encoding in a HTTP/1.0 request.");
+        return Err(StdServerError::BadRequest.to_error());
+    }
+    request.set_body(message.body);
+    // Trailer fields are kept apart, a client can't use them to add or override headers
+    for (name, value) in message.trailers.iter() {
+        request.append_trailer(name, value);
     }
 
     Ok(request)
 }
 
-/// Serialise a struct the implments Response into raw bytes for transfer to client
-pub fn serialize_into_response<R: Response>(response: &R) -> String {
+/// Serialised response head and the body to send after it
+struct Outgoing<'a> {
+    head: Vec<u8>,
+    /// `None` when only the head is sent
+    body: Option<&'a Body>,
+    chunked: bool,
+    /// The head announces the connection closes after it, whatever the response asked for
+    close: bool,
+}
+
+/// Serialise the status line and headers of a response and decide how the body is framed.
+/// Full bodies are sent with a Content-Length, stream bodies with chunked transfer coding
+/// unless the handler set a Content-Length. HTTP/1.0 clients can't decode chunked bodies,
+/// so streams are sent as-is and delimited by closing the connection.
+/// Responses to HEAD keep their headers but not the body.
+fn prepare_response<'a, T: Request, R: Response>(request: &T, response: &'a R) -> Outgoing<'a> {
     let body = response.get_body();
-    
+
     let status_code = match response.get_status_code() {
         Some(val) => val,
         None => {
-            return serialize_error_into_response(StdServerError::InternalServerError.to_error());
+            let error =
+                serialize_error_into_response(StdServerError::InternalServerError.to_error(), request.get_version());
+            return Outgoing {
+                head: error.into_bytes(),
+                body: None,
+                chunked: false,
+                close: true,
+            };
         }
     };
-    
+
+    // 1xx, 204 and 304 responses never have a body
+    let has_body = !matches!(status_code, 100..=199 | 204 | 304);
+
     let mut header = String::new();
-    match response.get_header("Content-Length") {
-        Some(_) => {},
-        None => {
-            header.push_str(&format!("{}: {}\r\n", "Content-Length", body.len().to_string().as_str()));
-        }
+    let version = request.get_version();
+    let chunked = has_body
+        && version.supports_chunked()
+        && body.is_stream()
+        && response.get_header("Content-Length").is_none();
+    if chunked {
+        header.push_str("Transfer-Encoding: chunked\r\n");
+    } else if let (true, None, Some(length)) =
+        (has_body, response.get_header("Content-Length"), body.len())
+    {
+        header.push_str(&format!("{}: {}\r\n", "Content-Length", length));
     }
     for (key, val) in response.get_headers().iter() {
         header.push_str(&format!("{}: {}\r\n", key, val));
     }
 
-    let status = match status_code {
-        200 => "OK",
-        201 => "Created",
-        _ => "",
+    let status_line = format!("{} {} {}", version, status_code, types::reason_phrase(status_code));
+    let head = format!("{}\r\n{}\r\n", status_line, header);
+
+    debug!("{}", head);
+
+    let send_body = has_body && request.get_method() != HttpMethod::Head;
+    Outgoing {
+        head: head.into_bytes(),
+        body: if send_body { Some(body) } else { None },
+        chunked,
+        close: false,
+    }
+}
+
+/// Serialise a struct the implments Response into raw bytes and write them to the client.
+/// Returns whether the head written told the client the connection closes, such as a 500
+/// sent in place of a response without a status code.
+pub fn write_response<T: Request, R: Response, W: Write>(
+    writer: &mut W,
+    request: &T,
+    response: &R,
+) -> io::Result<bool> {
+    let Outgoing { head, body, chunked, close } = prepare_response(request, response);
+
+    let written = match body {
+        None => {
+            writer.write_all(&head)?;
+            writer.flush()
+        }
+        Some(Body::Full(val)) => {
+            let mut message = head;
+            message.extend_from_slice(val);
+            writer.write_all(&message)?;
+            writer.flush()
+        }
+        Some(body) => {
+            writer.write_all(&head)?;
+            if let Some(chunks) = body.take_stream() {
+                for chunk in chunks {
+                    let chunk = chunk?;
+                    // a zero length chunk would end the body early
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    if chunked {
+                        writer.write_all(format!("{:X}\r\n", chunk.len()).as_bytes())?;
+                        writer.write_all(&chunk)?;
+                        writer.write_all(b"\r\n")?;
+                    } else {
+                        writer.write_all(&chunk)?;
+                    }
+                    writer.flush()?;
+                }
+            }
+            if chunked {
+                writer.write_all(b"0\r\n\r\n")?;
+            }
+            writer.flush()
+        }
     };
+    written.map(|_| close)
+}
 
-    let status_line = format!("HTTP/1.1 {} {}", status_code, status);
-    let response = format!("{}\r\n{}\r\n{}", status_line, header, body);
+/// Async counterpart of `write_response`.
+/// Stream bodies are still produced by a blocking iterator, each chunk is written as it is produced.
+pub async fn write_response_async<T: Request, R: Response, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    request: &T,
+    response: &R,
+) -> io::Result<bool> {
+    let Outgoing { head, body, chunked, close } = prepare_response(request, response);
+
+    let written = match body {
+        None => {
+            writer.write_all(&head).await?;
+            writer.flush().await
+        }
+        Some(Body::Full(val)) => {
+            let mut message = head;
+            message.extend_from_slice(val);
+            writer.write_all(&message).await?;
+            writer.flush().await
+        }
+        Some(body) => {
+            writer.write_all(&head).await?;
+            if let Some(chunks) = body.take_stream() {
+                let mut chunks = spawn_chunks(chunks);
+                while let Some(chunk) = chunks.recv().await {
+                    let chunk = chunk?;
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    if chunked {
+                        writer.write_all(format!("{:X}\r\n", chunk.len()).as_bytes()).await?;
+                        writer.write_all(&chunk).await?;
+                        writer.write_all(b"\r\n").await?;
+                    } else {
+                        writer.write_all(&chunk).await?;
+                    }
+                    writer.flush().await?;
+                }
+            }
+            if chunked {
+                writer.write_all(b"0\r\n\r\n").await?;
+            }
+            writer.flush().await
+        }
+    };
+    written.map(|_| close)
+}
 
-    debug!("{}", response);
+/// Produce the chunks of a stream body on the blocking thread pool, stream bodies may read files
+/// or wait on other blocking sources. One chunk is produced ahead of the one being written,
+/// the producer stops once the receiver is dropped.
+fn spawn_chunks(chunks: Chunks) -> mpsc::Receiver<io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::task::spawn_blocking(move || {
+        for chunk in chunks {
+            if sender.blocking_send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
+/// Turn an error into a response for middleware to see, the connection is closed after it
+pub fn error_into_response<R: Response>(error: &impl Error) -> R {
+    let mut response = R::new();
+    response.set_status_code(error.get_status_code());
+    for (key, val) in error.get_headers().iter() {
+        response.append_header(key, val);
+    }
+    response.set_header("Connection", "close");
     response
 }
 
-pub fn serialize_error_into_response(error: impl Error) -> String {
-    format!(
-        "HTTP/1.1 {} {}\r\n\r\n",
-        error.get_status_code(),
-        error.get_detail()
-    )
+/// Serialise an error into a response for a request sent with `version`,
+/// the same response `error_into_response` gives middleware. The connection is always closed after an error.
+pub fn serialize_error_into_response(error: impl Error, version: HttpVersion) -> String {
+    let mut request = HttpRequest::new();
+    request.set_version(version);
+    let response: HttpResponse = error_into_response(&error);
+    // Errors have no body, only the head is written
+    String::from_utf8_lossy(&prepare_response(&request, &response).head).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::reader::tests::read_message;
+
+    #[test]
+    fn test_parse_query_params() {
+        let raw = "GET /search?q=rust+http&tag=a&tag=b%26c HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = parse_into_request::<HttpRequest>(read_message(raw.as_bytes())).unwrap();
+        assert_eq!(request.get_path(), "/search");
+        assert_eq!(request.get_query_param("q"), Some(String::from("rust http")));
+        assert_eq!(request.get_query_param("tag"), Some(String::from("a")));
+        assert_eq!(request.get_query_params("tag"), vec![String::from("a"), String::from("b&c")]);
+        assert_eq!(request.get_query_param("page"), None);
+    }
+
+    #[test]
+    fn test_parse_repeated_headers() {
+        let raw = "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: text/html\r\naccept: */*\r\n\r\n";
+        let request = parse_into_request::<HttpRequest>(read_message(raw.as_bytes())).unwrap();
+        assert_eq!(request.get_header("host"), Some(String::from("localhost")));
+        assert_eq!(request.get_headers().get_all("Accept"), vec!["text/html", "*/*"]);
+    }
+
+    #[test]
+    fn test_write_headers_in_order() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_header("Content-Type", "text/plain");
+        response.append_header("Set-Cookie", "a=1");
+        response.append_header("Set-Cookie", "b=2");
+        response.set_body("ok");
+        assert_eq!(
+            written(&response),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Type: text/plain\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\nok"
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        let raw = "GET / HTTP/1.0\r\n\r\n";
+        let request = parse_into_request::<HttpRequest>(read_message(raw.as_bytes())).unwrap();
+        assert_eq!(request.get_version(), HttpVersion::V1_0);
+
+        let raw = "GET / HTTP/2.0\r\n\r\n";
+        let error = parse_into_request::<HttpRequest>(read_message(raw.as_bytes())).err().unwrap();
+        assert!(serialize_error_into_response(error, HttpVersion::V1_1)
+            .starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+
+        let raw = "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let result = parse_into_request::<HttpRequest>(read_message(raw.as_bytes()));
+        assert_eq!(result.err().map(|e| e.get_status_code()), Some(400));
+    }
+
+    #[test]
+    fn test_serialize_error_uses_reason_phrase() {
+        let error = ServerError::new(401, String::from("Token expired")).with_header("WWW-Authenticate", "Bearer");
+        let expected = "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nWWW-Authenticate: Bearer\r\nConnection: close\r\n\r\n";
+        // Errors look the same on the wire whether or not middleware saw them first
+        let response: HttpResponse = error_into_response(&error);
+        assert_eq!(written(&response), expected);
+        assert_eq!(serialize_error_into_response(error, HttpVersion::V1_1), expected);
+        let error = StdServerError::NotFound.to_error();
+        assert!(serialize_error_into_response(error, HttpVersion::V1_0).starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_write_stream_response_to_http10() {
+        let mut request = HttpRequest::new();
+        request.set_version(HttpVersion::V1_0);
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body(Body::from_chunks(vec!["hello", ", world"].into_iter()));
+        let mut buffer = Vec::<u8>::new();
+        write_response(&mut buffer, &request, &response).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "HTTP/1.0 200 OK\r\n\r\nhello, world"
+        );
+    }
+
+    #[test]
+    fn test_parse_chunked_request() {
+        let raw = "POST /files/a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nChecksum: abc\r\n\r\n";
+        let request = parse_into_request::<HttpRequest>(read_message(raw.as_bytes())).unwrap();
+        assert_eq!(request.get_body_text(), Some(String::from("hello, world")));
+        assert_eq!(request.get_header("Checksum"), None);
+        assert_eq!(request.get_trailer("Checksum"), Some(String::from("abc")));
+    }
+
+    fn written_for(method: HttpMethod, response: &HttpResponse) -> String {
+        let mut request = HttpRequest::new();
+        request.set_method(method);
+        let mut buffer = Vec::<u8>::new();
+        write_response(&mut buffer, &request, response).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn written(response: &HttpResponse) -> String {
+        written_for(HttpMethod::Get, response)
+    }
+
+    #[test]
+    fn test_parse_binary_request_body() {
+        let mut raw = b"POST /files/a.png HTTP/1.1\r\nContent-Length: 6\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0x89, b'P', b'N', b'G', 0x00, 0xff]);
+        let request = parse_into_request::<HttpRequest>(read_message(&raw)).unwrap();
+        assert_eq!(request.get_body(), Bytes::from_static(&[0x89, b'P', b'N', b'G', 0x00, 0xff]));
+        assert_eq!(request.get_body_text(), None);
+    }
+
+    #[test]
+    fn test_write_binary_response() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body(vec![0x00, 0xff]);
+        let mut buffer = Vec::<u8>::new();
+        write_response(&mut buffer, &HttpRequest::new(), &response).unwrap();
+        assert!(buffer.ends_with(b"Content-Length: 2\r\n\r\n\x00\xff"));
+    }
+
+    #[test]
+    fn test_write_response_without_status_closes() {
+        let mut buffer = Vec::<u8>::new();
+        let closed = write_response(&mut buffer, &HttpRequest::new(), &HttpResponse::new()).unwrap();
+        assert!(closed);
+        assert!(String::from_utf8(buffer).unwrap().contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_write_full_response() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body("hello");
+        assert_eq!(written(&response), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    #[test]
+    fn test_write_stream_response() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body(Body::from_chunks(vec!["hello", "", ", world"].into_iter()));
+        assert_eq!(
+            written(&response),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_stream_response_async() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body(Body::from_chunks(vec!["hello", "", ", world"].into_iter()));
+        let mut buffer = Vec::<u8>::new();
+        write_response_async(&mut buffer, &HttpRequest::new(), &response).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_write_stream_response_with_length() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_header("Content-Length", "12");
+        response.set_body(Body::from_reader(io::Cursor::new("hello, world")));
+        assert_eq!(written(&response), "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nhello, world");
+    }
+
+    #[test]
+    fn test_write_head_response() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(200);
+        response.set_body("hello");
+        assert_eq!(
+            written_for(HttpMethod::Head, &response),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_write_no_content_response() {
+        let mut response = HttpResponse::new();
+        response.set_status_code(204);
+        response.set_body("ignored");
+        assert_eq!(written(&response), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
 }
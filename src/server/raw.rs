@@ -0,0 +1,315 @@
+use crate::server::reader::ReadTimeout;
+use crate::server::traits::RawConnection;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const READ_SIZE: usize = 4096;
+
+/// Connection stream that passes the bytes read through `RawConnection::on_request`,
+/// in the order the middleware was added, and the bytes written through `on_response` in reverse.
+/// Without middleware bytes go straight to the inner stream.
+pub(crate) struct RawStream<S> {
+    inner: S,
+    connections: Vec<Box<dyn RawConnection>>,
+    /// Transformed bytes read but not handed out yet
+    inbound: Vec<u8>,
+    /// Transformed bytes not written yet, only used by the async stream
+    outbound: Vec<u8>,
+    /// The inner stream is done and the middleware has handed out what it held back
+    eof: bool,
+}
+
+impl<S> RawStream<S> {
+    pub(crate) fn new(inner: S, connections: Vec<Box<dyn RawConnection>>) -> Self {
+        Self {
+            inner,
+            connections,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            eof: false,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn on_request(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.connections.iter_mut().fold(bytes, |bytes, connection| connection.on_request(bytes))
+    }
+
+    fn on_response(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.connections.iter_mut().rev().fold(bytes, |bytes, connection| connection.on_response(bytes))
+    }
+
+    /// Bytes the middleware held back once the inner stream is done,
+    /// what one hands out still goes through `on_request` of the middleware after it
+    fn on_eof(&mut self) -> Vec<u8> {
+        self.eof = true;
+        self.connections.iter_mut().fold(Vec::new(), |bytes, connection| {
+            let mut bytes = if bytes.is_empty() { bytes } else { connection.on_request(bytes) };
+            bytes.extend(connection.on_eof());
+            bytes
+        })
+    }
+
+    /// Copy buffered inbound bytes into `buf`, returns how many were copied
+    fn take_inbound(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.inbound.len());
+        buf[..n].copy_from_slice(&self.inbound[..n]);
+        self.inbound.drain(..n);
+        n
+    }
+}
+
+impl<S: Read> Read for RawStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.connections.is_empty() {
+            return self.inner.read(buf);
+        }
+        // Middleware may hold bytes back, keep reading until it hands some out or the stream closes
+        while self.inbound.is_empty() {
+            if self.eof {
+                return Ok(0);
+            }
+            let mut chunk = [0; READ_SIZE];
+            let n = self.inner.read(&mut chunk)?;
+            self.inbound = match n {
+                0 => self.on_eof(),
+                _ => self.on_request(chunk[..n].to_vec()),
+            };
+        }
+        Ok(self.take_inbound(buf))
+    }
+}
+
+impl<S: Write> Write for RawStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.connections.is_empty() {
+            return self.inner.write(buf);
+        }
+        let bytes = self.on_response(buf.to_vec());
+        self.inner.write_all(&bytes)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: ReadTimeout> ReadTimeout for RawStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RawStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.connections.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        while this.inbound.is_empty() {
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; READ_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            this.inbound = match chunk.filled() {
+                [] => this.on_eof(),
+                val => this.on_request(val.to_vec()),
+            };
+        }
+        let n = this.take_inbound(buf.initialize_unfilled());
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> RawStream<S> {
+    /// Write out the transformed bytes left from earlier writes
+    fn poll_outbound(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.outbound.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outbound)) {
+                Ok(0) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    self.outbound.drain(..n);
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RawStream<S> {
+    /// Transformed bytes may differ in length from `buf`, they are buffered
+    /// and written out by the following write, flush or shutdown
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.connections.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_outbound(cx))?;
+        this.outbound = this.on_response(buf.to_vec());
+        // Start sending now, whatever is left waits for the next call
+        if let Poll::Ready(Err(e)) = this.poll_outbound(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outbound(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outbound(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::server::traits::{ConnectionInfo, RawMiddleware};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Raw middleware counting the bytes read and written over all connections, and the connections
+    #[derive(Clone, Default)]
+    pub(crate) struct ByteCounter {
+        pub(crate) read: Arc<AtomicUsize>,
+        pub(crate) written: Arc<AtomicUsize>,
+        pub(crate) connections: Arc<Mutex<Vec<ConnectionInfo>>>,
+    }
+
+    impl RawMiddleware for ByteCounter {
+        fn connection(&self, info: &ConnectionInfo) -> Box<dyn RawConnection> {
+            self.connections.lock().unwrap().push(info.clone());
+            Box::new(self.clone())
+        }
+    }
+
+    impl RawConnection for ByteCounter {
+        fn on_request(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+            self.read.fetch_add(bytes.len(), Ordering::Relaxed);
+            bytes
+        }
+
+        fn on_response(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+            self.written.fetch_add(bytes.len(), Ordering::Relaxed);
+            bytes
+        }
+    }
+
+    /// Drops `\r` from the bytes read and writes them back before each `\n`
+    struct LineEndings;
+
+    impl RawConnection for LineEndings {
+        fn on_request(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+            bytes.into_iter().filter(|i| *i != b'\r').collect()
+        }
+
+        fn on_response(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+            let mut out = Vec::new();
+            for i in bytes {
+                if i == b'\n' {
+                    out.push(b'\r');
+                }
+                out.push(i);
+            }
+            out
+        }
+    }
+
+    /// Hands out the bytes read a line at a time, holding back a line that isn't finished
+    #[derive(Default)]
+    struct WholeLines {
+        partial: Vec<u8>,
+    }
+
+    impl RawConnection for WholeLines {
+        fn on_request(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+            self.partial.extend(bytes);
+            match self.partial.iter().rposition(|i| *i == b'\n') {
+                Some(val) => {
+                    let rest = self.partial.split_off(val + 1);
+                    std::mem::replace(&mut self.partial, rest)
+                }
+                None => Vec::new(),
+            }
+        }
+
+        fn on_eof(&mut self) -> Vec<u8> {
+            std::mem::take(&mut self.partial)
+        }
+    }
+
+    #[test]
+    fn test_raw_stream_transforms_both_directions() {
+        let counter = ByteCounter::default();
+        // The counter is added first, so it sees requests before and responses after the transform
+        let mut stream = RawStream::new(
+            &b"\r\n\r\nGET / HTTP/1.1\r\n\r\n"[..],
+            vec![Box::new(counter.clone()), Box::new(LineEndings)],
+        );
+        let mut read = String::new();
+        stream.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "\n\nGET / HTTP/1.1\n\n");
+        assert_eq!(counter.read.load(Ordering::Relaxed), 22);
+
+        let mut stream = RawStream::new(
+            Vec::new(),
+            vec![Box::new(counter.clone()), Box::new(LineEndings)],
+        );
+        stream.write_all(b"HTTP/1.1 200 OK\n\n").unwrap();
+        assert_eq!(stream.into_inner(), b"HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(counter.written.load(Ordering::Relaxed), 19);
+    }
+
+    #[test]
+    fn test_raw_stream_hands_out_held_bytes_at_eof() {
+        let counter = ByteCounter::default();
+        // What the first middleware held back still goes through the counter after it
+        let mut stream = RawStream::new(
+            &b"GET / HTTP/1.1\nunfinished"[..],
+            vec![Box::new(WholeLines::default()), Box::new(counter.clone())],
+        );
+        let mut read = String::new();
+        stream.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "GET / HTTP/1.1\nunfinished");
+        assert_eq!(counter.read.load(Ordering::Relaxed), 25);
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_raw_stream_transforms_both_directions() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = RawStream::new(&b"\r\n\r\nGET / HTTP/1.1\r\n\r\n"[..], vec![Box::new(LineEndings)]);
+        let mut read = String::new();
+        AsyncReadExt::read_to_string(&mut stream, &mut read).await.unwrap();
+        assert_eq!(read, "\n\nGET / HTTP/1.1\n\n");
+
+        let mut stream = RawStream::new(&b"partial"[..], vec![Box::new(WholeLines::default())]);
+        let mut read = String::new();
+        AsyncReadExt::read_to_string(&mut stream, &mut read).await.unwrap();
+        assert_eq!(read, "partial");
+
+        let mut stream = RawStream::new(Vec::new(), vec![Box::new(LineEndings)]);
+        AsyncWriteExt::write_all(&mut stream, b"HTTP/1.1 200 OK\n").await.unwrap();
+        AsyncWriteExt::write_all(&mut stream, b"\n").await.unwrap();
+        AsyncWriteExt::flush(&mut stream).await.unwrap();
+        assert_eq!(stream.into_inner(), b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+}
@@ -0,0 +1,875 @@
+use crate::http::http11::{self, Chunked, ChunkedDecoder, ParseStatus};
+use crate::http::types::{HttpVersion, RequestLine};
+use crate::server::error::{ServerError, StdServerError};
+
+use bytes::Bytes;
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const READ_SIZE: usize = 4096;
+
+/// How the end of the body being read is found
+enum Framing {
+    /// Body of a known length, holds the position where the message ends
+    Length(usize),
+    /// Chunked body, decoded as it arrives. The decoded body can't be longer than `limit`,
+    /// the chunk framing around it is held to the header size limit.
+    Chunked { limit: usize, decoder: ChunkedDecoder },
+}
+
+/// Request whose head has been parsed while its body is still arriving
+struct PendingMessage {
+    request_line: RequestLine,
+    headers: Vec<(String, String)>,
+    body_start: usize,
+    framing: Framing,
+}
+
+/// Progress finding the end of the request line and header block,
+/// so bytes already searched aren't searched again on the next read
+#[derive(Default)]
+struct HeadScan {
+    /// Bytes of the buffer already searched
+    scanned: usize,
+    /// Position of the CRLF ending the request line
+    line_end: Option<usize>,
+    /// Position just past the empty line ending the header block
+    head_end: Option<usize>,
+}
+
+impl HeadScan {
+    fn scan(&mut self, buffer: &[u8]) {
+        // A CRLF may straddle the bytes searched before and the new ones
+        if self.line_end.is_none() {
+            self.line_end = find(buffer, b"\r\n", self.scanned.saturating_sub(1));
+        }
+        if self.head_end.is_none() {
+            self.head_end = find(buffer, b"\r\n\r\n", self.scanned.saturating_sub(3)).map(|i| i + 4);
+        }
+        self.scanned = buffer.len();
+    }
+}
+
+/// A complete request read from a connection, the head has been parsed and the body decoded
+#[derive(Debug, PartialEq)]
+pub struct RequestMessage {
+    pub request_line: RequestLine,
+    /// Header fields in the order they were sent
+    pub headers: Vec<(String, String)>,
+    /// Body with any transfer coding removed
+    pub body: Bytes,
+    /// Trailer fields sent after a chunked body
+    pub trailers: Vec<(String, String)>,
+}
+
+impl RequestMessage {
+    /// Version a response to this message is sent with, only HTTP/1.0 clients need something other than HTTP/1.1
+    pub fn response_version(&self) -> HttpVersion {
+        match self.request_line.version {
+            HttpVersion::V1_0 => HttpVersion::V1_0,
+            _ => HttpVersion::V1_1,
+        }
+    }
+}
+
+/// Size limits for requests read from a connection
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+    /// Longest request line, longer ones are answered with 414
+    pub request_line: usize,
+    /// Most bytes of header fields, more are answered with 431.
+    /// Also bounds the chunk framing and trailer fields of a chunked body, more is answered with 413.
+    pub header_bytes: usize,
+    /// Most header fields, more are answered with 431
+    pub headers: usize,
+    /// Largest body, larger ones are answered with 413
+    pub body: usize,
+}
+
+/// Body size limit for a request method and target, `None` to use the default limit
+pub type BodyLimit = Arc<dyn Fn(&str, &str) -> Option<usize> + Send + Sync>;
+
+/// How long each stage of reading a request may take
+#[derive(Clone, Copy, Debug)]
+pub struct ReadTimeouts {
+    /// Waiting for the first byte of the next request, the connection is closed quietly after it
+    pub idle: Duration,
+    /// Receiving the request line and headers once the request has started
+    pub header: Duration,
+    /// Receiving the body once the headers have arrived
+    pub body: Duration,
+}
+
+/// Streams that can limit how long a blocking read waits
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for &TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Outcome of reading more bytes from a stream
+enum Filled {
+    Data,
+    Closed,
+    TimedOut,
+}
+
+/// Bytes read from a connection that have not been handed out as a request yet.
+/// Shared by the blocking and async readers, which only differ in how they fill it.
+struct MessageBuffer {
+    buffer: Vec<u8>,
+    scan: HeadScan,
+    pending: Option<PendingMessage>,
+    timeouts: ReadTimeouts,
+    /// When the stage of the request being received times out, `None` while idle
+    deadline: Option<Instant>,
+    limits: RequestLimits,
+    body_limit: Option<BodyLimit>,
+    /// Version of the latest request line received, errors are answered with it
+    version: HttpVersion,
+}
+
+impl MessageBuffer {
+    fn new(timeouts: ReadTimeouts, limits: RequestLimits) -> Self {
+        Self {
+            buffer: Vec::new(),
+            scan: HeadScan::default(),
+            pending: None,
+            timeouts,
+            deadline: None,
+            limits,
+            body_limit: None,
+            version: HttpVersion::V1_1,
+        }
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.timeouts.header);
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.buffer.is_empty() && self.pending.is_none()
+    }
+
+    /// Take the next complete message from the buffer, `None` if more bytes are needed
+    fn next_message(&mut self) -> Result<Option<RequestMessage>, ServerError> {
+        if self.pending.is_none() && !self.read_head()? {
+            return Ok(None);
+        }
+        let pending = match self.pending.as_mut() {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+
+        let (message_end, body, trailers) = match &mut pending.framing {
+            Framing::Length(end) if self.buffer.len() >= *end => (*end, None, Vec::new()),
+            Framing::Length(_) => return Ok(None),
+            // Wait for the last chunk and trailer section
+            Framing::Chunked { limit, decoder } => {
+                let result = decoder.decode(&self.buffer[pending.body_start..]);
+                let (decoded, encoded) = match &result {
+                    Chunked::Complete { body, length, .. } => (body.len(), *length),
+                    _ => (decoder.decoded(), self.buffer.len() - pending.body_start),
+                };
+                if decoded > *limit {
+                    log::debug!("Chunked request body is over the {} byte limit.", limit);
+                    return Err(StdServerError::ContentTooLarge.to_error());
+                }
+                // Chunk sizes, extensions and trailer fields are limited like header fields
+                if encoded - decoded > self.limits.header_bytes {
+                    log::debug!("Chunk framing and trailers are over {} bytes.", self.limits.header_bytes);
+                    return Err(StdServerError::ContentTooLarge.to_error());
+                }
+                match result {
+                    Chunked::Complete { body, trailers, length } => {
+                        (pending.body_start + length, Some(Bytes::from(body)), trailers)
+                    }
+                    Chunked::Incomplete => return Ok(None),
+                    Chunked::TooLarge => {
+                        log::debug!("Chunk size is over the {} byte body limit.", limit);
+                        return Err(StdServerError::ContentTooLarge.to_error());
+                    }
+                    Chunked::Invalid => {
+                        log::debug!("Invalid chunked request body.");
+                        return Err(StdServerError::BadRequest.to_error());
+                    }
+                }
+            }
+        };
+
+        let (request_line, headers, body_start) = match self.pending.take() {
+            Some(val) => (val.request_line, val.headers, val.body_start),
+            None => return Ok(None),
+        };
+        let body = body.unwrap_or_else(|| Bytes::copy_from_slice(&self.buffer[body_start..message_end]));
+        self.buffer.drain(..message_end);
+        self.scan = HeadScan::default();
+        // A pipelined request may already have started
+        self.deadline = match self.buffer.is_empty() {
+            true => None,
+            false => Some(Instant::now() + self.timeouts.header),
+        };
+        Ok(Some(RequestMessage {
+            request_line,
+            headers,
+            body,
+            trailers,
+        }))
+    }
+
+    /// Parse the header block once it has all arrived and work out how the body is framed.
+    /// Returns whether the head is complete.
+    fn read_head(&mut self) -> Result<bool, ServerError> {
+        // Ignore empty lines sent ahead of a request line
+        if self.scan.scanned == 0 {
+            let skip = self.buffer.iter().take_while(|i| **i == b'\r' || **i == b'\n').count();
+            self.buffer.drain(..skip);
+        }
+
+        self.scan.scan(&self.buffer);
+        self.version = self.scan.line_end.map_or(HttpVersion::V1_1, |i| request_version(&self.buffer[..i]));
+        let head_end = match self.scan.head_end {
+            Some(val) => val,
+            None => {
+                self.check_head_size(self.buffer.len())?;
+                return Ok(false);
+            }
+        };
+        self.check_head_size(head_end)?;
+
+        let head = match http11::parse_request_head(&self.buffer[..head_end]) {
+            ParseStatus::Complete(val) => val,
+            ParseStatus::Partial => {
+                log::debug!("Malformed request head.");
+                return Err(StdServerError::BadRequest.to_error());
+            }
+            ParseStatus::Error(position) => {
+                log::debug!("Malformed request head at byte {}.", position);
+                return Err(StdServerError::BadRequest.to_error());
+            }
+        };
+        if head.headers.len() > self.limits.headers {
+            log::debug!("Request has more than {} header fields.", self.limits.headers);
+            return Err(StdServerError::RequestHeaderFieldsTooLarge.to_error());
+        }
+        let body_limit = match &self.body_limit {
+            Some(f) => f(head.method, head.target),
+            None => None,
+        };
+        let body_limit = body_limit.unwrap_or(self.limits.body);
+        let request_line = head.request_line()?;
+        let headers = head.fields();
+
+        let framing = if http11::is_chunked(&headers)? {
+            // Which of the two frames the body is ambiguous, a proxy in front may have picked the other one
+            if headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+                log::debug!("Request has both Content-Length and Transfer-Encoding.");
+                return Err(StdServerError::BadRequest.to_error());
+            }
+            Framing::Chunked {
+                limit: body_limit,
+                decoder: ChunkedDecoder::with_limit(body_limit),
+            }
+        } else {
+            let length = content_length(&headers)?;
+            if length > body_limit {
+                log::debug!("Content-Length {} is over the {} byte limit.", length, body_limit);
+                return Err(StdServerError::ContentTooLarge.to_error());
+            }
+            Framing::Length(head_end + length)
+        };
+        self.pending = Some(PendingMessage {
+            request_line,
+            headers,
+            body_start: head_end,
+            framing,
+        });
+        self.deadline = Some(Instant::now() + self.timeouts.body);
+        Ok(true)
+    }
+
+    /// Reject a request head, received up to `head_end`, that is over the size limits
+    fn check_head_size(&self, head_end: usize) -> Result<(), ServerError> {
+        let line_end = self.scan.line_end;
+        if line_end.unwrap_or(head_end) > self.limits.request_line {
+            log::debug!("Request line is longer than {} bytes.", self.limits.request_line);
+            return Err(StdServerError::UriTooLong.to_error());
+        }
+        let header_bytes = line_end.map_or(0, |i| head_end - (i + 2));
+        if header_bytes > self.limits.header_bytes {
+            log::debug!("Request header fields are over {} bytes.", self.limits.header_bytes);
+            return Err(StdServerError::RequestHeaderFieldsTooLarge.to_error());
+        }
+        Ok(())
+    }
+
+    /// How long the next read may wait, a 408 error once the current stage has run out of time
+    fn read_timeout(&self) -> Result<Duration, ServerError> {
+        let deadline = match self.deadline {
+            Some(val) => val,
+            None => return Ok(self.timeouts.idle),
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return self.timed_out().map(|_| remaining);
+        }
+        Ok(remaining)
+    }
+
+    /// Result of the stream closing before the next message was complete
+    fn closed(&self) -> Result<Option<RequestMessage>, ServerError> {
+        if self.is_idle() {
+            return Ok(None);
+        }
+        match self.pending {
+            Some(_) => log::debug!("Connection closed before the end of the request body."),
+            None => log::debug!("Connection closed before the end of the request headers."),
+        }
+        Err(StdServerError::BadRequest.to_error())
+    }
+
+    /// Result of a read timing out, idle connections are closed without a response
+    fn timed_out(&self) -> Result<Option<RequestMessage>, ServerError> {
+        if self.deadline.is_none() {
+            return Ok(None);
+        }
+        match self.pending {
+            Some(_) => log::debug!("Timed out reading the request body."),
+            None => log::debug!("Timed out reading the request headers."),
+        }
+        Err(StdServerError::RequestTimeout.to_error())
+    }
+}
+
+/// Splits the bytes read from a connection into complete request messages.
+/// Bytes read past the end of a message are kept for the next call, so
+/// pipelined requests on a persistent connection are not lost.
+pub struct RequestReader<S: Read + ReadTimeout> {
+    stream: S,
+    messages: MessageBuffer,
+}
+
+impl<S: Read + ReadTimeout> RequestReader<S> {
+    pub fn new(stream: S, timeouts: ReadTimeouts, limits: RequestLimits) -> Self {
+        Self {
+            stream,
+            messages: MessageBuffer::new(timeouts, limits),
+        }
+    }
+
+    /// Look up the body size limit of each request, such as from the route it will be dispatched to
+    pub fn with_body_limit(mut self, body_limit: BodyLimit) -> Self {
+        self.messages.body_limit = Some(body_limit);
+        self
+    }
+
+    /// Version of the request being read, or the last one read, for answering it with an error
+    pub fn version(&self) -> HttpVersion {
+        self.messages.version
+    }
+
+    /// The stream being read, such as to write responses to it
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Whether no part of the next request has been received yet
+    pub fn is_idle(&self) -> bool {
+        self.messages.is_idle()
+    }
+
+    /// Wait for the first bytes of the next request, returns straight away if part of it has been received.
+    /// Returns false when the stream closed, or went idle, before a new request started.
+    pub fn wait_for_request(&mut self) -> Result<bool, ServerError> {
+        if !self.is_idle() {
+            return Ok(true);
+        }
+        match self.fill()? {
+            Filled::Data => Ok(true),
+            Filled::Closed => self.messages.closed().map(|_| false),
+            Filled::TimedOut => self.messages.timed_out().map(|_| false),
+        }
+    }
+
+    /// Read the next request message from the stream.
+    /// Returns `None` when the stream closed, or went idle, before a new request started.
+    pub fn read_request(&mut self) -> Result<Option<RequestMessage>, ServerError> {
+        loop {
+            if let Some(message) = self.messages.next_message()? {
+                return Ok(Some(message));
+            }
+            match self.fill()? {
+                Filled::Data => {}
+                Filled::Closed => return self.messages.closed(),
+                Filled::TimedOut => return self.messages.timed_out(),
+            }
+        }
+    }
+
+    /// Read more bytes from the stream into the buffer
+    fn fill(&mut self) -> Result<Filled, ServerError> {
+        let timeout = self.messages.read_timeout()?;
+        // A zero timeout means no waiting at all, sockets would take it as an error
+        if timeout.is_zero() {
+            return Ok(Filled::TimedOut);
+        }
+        if let Err(e) = self.stream.set_read_timeout(Some(timeout)) {
+            log::debug!("Failed to set the read timeout: {:?}", e);
+            return Err(StdServerError::InternalServerError.to_error());
+        }
+
+        let mut chunk = [0; READ_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(Filled::Closed),
+                Ok(n) => {
+                    self.messages.extend(&chunk[..n]);
+                    return Ok(Filled::Data);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if is_timeout(&e) => return Ok(Filled::TimedOut),
+                Err(e) => {
+                    log::debug!("Failed to read from the connection: {:?}", e);
+                    return Err(StdServerError::BadRequest.to_error());
+                }
+            }
+        }
+    }
+}
+
+/// Async counterpart of `RequestReader`
+pub struct AsyncRequestReader<S: AsyncRead + Unpin> {
+    stream: S,
+    messages: MessageBuffer,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRequestReader<S> {
+    pub fn new(stream: S, timeouts: ReadTimeouts, limits: RequestLimits) -> Self {
+        Self {
+            stream,
+            messages: MessageBuffer::new(timeouts, limits),
+        }
+    }
+
+    /// Look up the body size limit of each request, such as from the route it will be dispatched to
+    pub fn with_body_limit(mut self, body_limit: BodyLimit) -> Self {
+        self.messages.body_limit = Some(body_limit);
+        self
+    }
+
+    /// Version of the request being read, or the last one read, for answering it with an error
+    pub fn version(&self) -> HttpVersion {
+        self.messages.version
+    }
+
+    /// Whether no part of the next request has been received yet
+    pub fn is_idle(&self) -> bool {
+        self.messages.is_idle()
+    }
+
+    /// Read the next request message from the stream.
+    /// Returns `None` when the stream closed, or went idle, before a new request started.
+    pub async fn read_request(&mut self) -> Result<Option<RequestMessage>, ServerError> {
+        loop {
+            if let Some(message) = self.messages.next_message()? {
+                return Ok(Some(message));
+            }
+            match self.fill().await? {
+                Filled::Data => {}
+                Filled::Closed => return self.messages.closed(),
+                Filled::TimedOut => return self.messages.timed_out(),
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> Result<Filled, ServerError> {
+        let timeout = self.messages.read_timeout()?;
+        let mut chunk = [0; READ_SIZE];
+        match tokio::time::timeout(timeout, self.stream.read(&mut chunk)).await {
+            Ok(Ok(0)) => Ok(Filled::Closed),
+            Ok(Ok(n)) => {
+                self.messages.extend(&chunk[..n]);
+                Ok(Filled::Data)
+            }
+            Ok(Err(e)) => {
+                log::debug!("Failed to read from the connection: {:?}", e);
+                Err(StdServerError::BadRequest.to_error())
+            }
+            Err(_) => Ok(Filled::TimedOut),
+        }
+    }
+}
+
+pub fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Position of the first `needle` at or after `start`
+fn find(data: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    data.get(start..)?
+        .windows(needle.len())
+        .position(|i| i == needle)
+        .map(|i| i + start)
+}
+
+/// Version a response to this request line is sent with, only HTTP/1.0 clients need something other than HTTP/1.1
+fn request_version(line: &[u8]) -> HttpVersion {
+    let version = line.rsplit(|i| *i == b' ').next().and_then(|i| std::str::from_utf8(i).ok());
+    match version.map(str::parse) {
+        Some(Ok(HttpVersion::V1_0)) => HttpVersion::V1_0,
+        _ => HttpVersion::V1_1,
+    }
+}
+
+/// Get the body length from the Content-Length header, a message without one has no body
+fn content_length(headers: &[(String, String)]) -> Result<usize, ServerError> {
+    let mut length: Option<usize> = None;
+    for (name, value) in headers {
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        // Content-Length = 1*DIGIT, parse would also take a leading sign
+        let digits = !value.is_empty() && value.bytes().all(|i| i.is_ascii_digit());
+        let value = match value.parse::<usize>() {
+            Ok(val) if digits => val,
+            _ => {
+                log::debug!("Invalid Content-Length: {}", value);
+                return Err(StdServerError::BadRequest.to_error());
+            }
+        };
+        // Repeated headers must agree
+        if length.is_some_and(|i| i != value) {
+            log::debug!("Conflicting Content-Length headers.");
+            return Err(StdServerError::BadRequest.to_error());
+        }
+        length = Some(value);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::server::traits::Error;
+
+    /// Stream that hands out its data in fixed size reads.
+    /// A stalled stream times out instead of closing once the data runs out.
+    struct SegmentedStream {
+        segments: Vec<Vec<u8>>,
+        stalled: bool,
+    }
+
+    impl Read for SegmentedStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.segments.is_empty() {
+                return match self.stalled {
+                    true => Err(ErrorKind::WouldBlock.into()),
+                    false => Ok(0),
+                };
+            }
+            let segment = self.segments.remove(0);
+            buf[..segment.len()].copy_from_slice(&segment);
+            Ok(segment.len())
+        }
+    }
+
+    impl ReadTimeout for SegmentedStream {
+        // Like a socket, a zero timeout is an error
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            match timeout {
+                Some(val) if val.is_zero() => Err(ErrorKind::InvalidInput.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn segmented(data: &str, size: usize) -> SegmentedStream {
+        SegmentedStream {
+            segments: data.as_bytes().chunks(size).map(|i| i.to_vec()).collect(),
+            stalled: false,
+        }
+    }
+
+    fn stalled(data: &str, size: usize) -> SegmentedStream {
+        SegmentedStream {
+            stalled: true,
+            ..segmented(data, size)
+        }
+    }
+
+    /// Message expected for a request whose body isn't chunked
+    fn message(request: &str) -> RequestMessage {
+        let head = match http11::parse_request_head(request.as_bytes()) {
+            ParseStatus::Complete(val) => val,
+            _ => panic!("incomplete request head"),
+        };
+        RequestMessage {
+            request_line: head.request_line().ok().unwrap(),
+            headers: head.fields(),
+            body: Bytes::copy_from_slice(&request.as_bytes()[head.body_offset..]),
+            trailers: Vec::new(),
+        }
+    }
+
+    /// Read a single request the way a connection would, for tests of code that takes a message
+    pub(crate) fn read_message(raw: &[u8]) -> RequestMessage {
+        let stream = SegmentedStream {
+            segments: vec![raw.to_vec()],
+            stalled: false,
+        };
+        let limits = RequestLimits {
+            request_line: 8192,
+            header_bytes: 8192,
+            headers: 100,
+            body: 1 << 20,
+        };
+        let mut reader = RequestReader::new(stream, timeouts(), limits);
+        reader.read_request().unwrap().unwrap()
+    }
+
+    fn timeouts() -> ReadTimeouts {
+        ReadTimeouts {
+            idle: Duration::from_secs(1),
+            header: Duration::from_secs(1),
+            body: Duration::from_secs(1),
+        }
+    }
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            request_line: 64,
+            header_bytes: 64,
+            headers: 4,
+            body: 32,
+        }
+    }
+
+    #[test]
+    fn test_read_limits() {
+        let status = |data: &str| {
+            let mut reader = RequestReader::new(segmented(data, 8), timeouts(), limits());
+            reader.read_request().err().map(|e| e.get_status_code())
+        };
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        assert_eq!(status(&long_path), Some(414));
+        // The request line is limited before it has all arrived
+        assert_eq!(status(&"a".repeat(100)), Some(414));
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(64));
+        assert_eq!(status(&long_header), Some(431));
+        assert_eq!(status("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\n\r\n"), Some(431));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 33\r\n\r\n"), Some(413));
+        let chunked = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n21\r\n{}\r\n0\r\n\r\n", "a".repeat(33));
+        assert_eq!(status(&chunked), Some(413));
+        let full = format!("POST / HTTP/1.1\r\nContent-Length: 32\r\n\r\n{}", "a".repeat(32));
+        assert_eq!(status(&full), None);
+        // The limit applies to the decoded body, not the chunk framing around it
+        let chunks = format!("8\r\n{}\r\n", "a".repeat(8)).repeat(4);
+        let chunked = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}0\r\n\r\n");
+        assert_eq!(status(&chunked), None);
+        let extension = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(64));
+        let chunked = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{extension}");
+        assert_eq!(status(&chunked), Some(413));
+    }
+
+    #[test]
+    fn test_read_body_limit_override() {
+        let data = format!("POST /upload HTTP/1.1\r\nContent-Length: 33\r\n\r\n{}", "a".repeat(33));
+        let body_limit: BodyLimit = Arc::new(|method, target| match (method, target) {
+            ("POST", "/upload") => Some(1024),
+            _ => None,
+        });
+        let mut reader =
+            RequestReader::new(segmented(&data, 8), timeouts(), limits()).with_body_limit(body_limit);
+        assert_eq!(reader.read_request().ok().flatten(), Some(message(&data)));
+    }
+
+    #[test]
+    fn test_read_timeouts() {
+        let timed_out = |stream| {
+            let mut reader = RequestReader::new(stream, timeouts(), limits());
+            reader.read_request().err().map(|e| e.get_status_code())
+        };
+        assert_eq!(timed_out(stalled("GET / HTTP/1.1\r\nHost: a", 1024)), Some(408));
+        assert_eq!(timed_out(stalled("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab", 1024)), Some(408));
+
+        // An idle connection is closed without a response
+        let mut reader = RequestReader::new(stalled("GET / HTTP/1.1\r\n\r\n", 1024), timeouts(), limits());
+        assert!(reader.read_request().unwrap().is_some());
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wait_for_request() {
+        let mut reader = RequestReader::new(segmented("GET / HTTP/1.1\r\n\r\n", 4), timeouts(), limits());
+        assert!(reader.wait_for_request().unwrap());
+        assert!(!reader.is_idle());
+        // Part of the request is buffered, so there is nothing to wait for
+        assert!(reader.wait_for_request().unwrap());
+        assert!(reader.read_request().unwrap().is_some());
+        assert!(reader.is_idle());
+        assert!(!reader.wait_for_request().unwrap());
+    }
+
+    #[test]
+    fn test_read_zero_idle_timeout() {
+        // An idle connection is closed straight away rather than failing to set the timeout
+        let timeouts = ReadTimeouts {
+            idle: Duration::ZERO,
+            ..timeouts()
+        };
+        let mut reader = RequestReader::new(stalled("", 1024), timeouts, limits());
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_header_deadline_spans_reads() {
+        // Trickling bytes in doesn't extend the time allowed for the headers
+        let timeouts = ReadTimeouts {
+            header: Duration::ZERO,
+            ..timeouts()
+        };
+        let mut reader = RequestReader::new(segmented("GET / HTTP/1.1\r\n\r\n", 1), timeouts, limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(408));
+    }
+
+    #[test]
+    fn test_read_request_across_segments() {
+        let request = "POST /files/a HTTP/1.1\r\nContent-Length: 12\r\n\r\nhello, world";
+        for size in [1, 7, 100, request.len()] {
+            let mut reader = RequestReader::new(segmented(request, size), timeouts(), limits());
+            assert_eq!(reader.read_request().ok().flatten(), Some(message(request)));
+            assert!(reader.read_request().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_read_pipelined_requests() {
+        let first = "POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc";
+        let second = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut reader = RequestReader::new(segmented(&format!("{first}{second}"), 1024), timeouts(), limits());
+
+        assert_eq!(reader.read_request().ok().flatten(), Some(message(first)));
+        assert_eq!(reader.read_request().ok().flatten(), Some(message(second)));
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_truncated_body_is_error() {
+        let mut reader = RequestReader::new(segmented(
+            "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+            1024,
+        ), timeouts(), limits());
+        assert!(reader.read_request().is_err());
+    }
+
+    #[test]
+    fn test_read_invalid_content_length_is_error() {
+        for length in ["ten", "+5", "-5", "5, 5", "0x5", ""] {
+            let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabcde", length);
+            let mut reader = RequestReader::new(segmented(&request, 1024), timeouts(), limits());
+            assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400), "{}", length);
+        }
+
+        // Repeated values must agree
+        let request = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nabcdef";
+        let mut reader = RequestReader::new(segmented(request, 1024), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400));
+        let request = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nabcde";
+        let mut reader = RequestReader::new(segmented(request, 1024), timeouts(), limits());
+        assert!(reader.read_request().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_read_version_for_errors() {
+        let long_header = format!("GET / HTTP/1.0\r\nX-Long: {}\r\n\r\n", "a".repeat(64));
+        let mut reader = RequestReader::new(segmented(&long_header, 8), timeouts(), limits());
+        assert!(reader.read_request().is_err());
+        assert_eq!(reader.version(), HttpVersion::V1_0);
+
+        // Versions other than HTTP/1.0 are answered with HTTP/1.1
+        for request in ["GET / HTTP/2.0\r\n\r\n", "GET / HTTP/1.", "GET / HTTP/1.0"] {
+            let mut reader = RequestReader::new(segmented(request, 1024), timeouts(), limits());
+            let _ = reader.read_request();
+            assert_eq!(reader.version(), HttpVersion::V1_1, "{}", request);
+        }
+    }
+
+    #[test]
+    fn test_read_malformed_head_is_error() {
+        let mut reader = RequestReader::new(segmented("GET / HTTP/1.1\r\nHost localhost\r\n\r\n", 3), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400));
+        // HTTP-version is DIGIT "." DIGIT, the minor version can't be left out
+        let mut reader = RequestReader::new(segmented("GET / HTTP/1\r\n\r\n", 3), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400));
+    }
+
+    #[tokio::test]
+    async fn test_async_read_pipelined_requests() {
+        let first = "POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc";
+        let second = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let data = format!("{first}{second}");
+        let mut reader = AsyncRequestReader::new(data.as_bytes(), timeouts(), limits());
+
+        assert_eq!(reader.read_request().await.ok().flatten(), Some(message(first)));
+        assert_eq!(reader.read_request().await.ok().flatten(), Some(message(second)));
+        assert!(reader.read_request().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_chunked_request() {
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let first = format!("{head}3\r\nabc\r\n0\r\nChecksum: 1\r\n\r\n");
+        let second = "GET / HTTP/1.1\r\n\r\n";
+        let decoded = || RequestMessage {
+            body: Bytes::from_static(b"abc"),
+            trailers: vec![(String::from("Checksum"), String::from("1"))],
+            ..message(head)
+        };
+        for size in [1, 5, 1024] {
+            let mut reader = RequestReader::new(segmented(&format!("{first}{second}"), size), timeouts(), limits());
+            assert_eq!(reader.read_request().ok().flatten(), Some(decoded()));
+            assert_eq!(reader.read_request().ok().flatten(), Some(message(second)));
+        }
+    }
+
+    #[test]
+    fn test_read_chunked_request_with_length_is_error() {
+        let mut reader = RequestReader::new(segmented(
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+            1024,
+        ), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400));
+    }
+
+    #[test]
+    fn test_read_huge_chunk_size_is_error() {
+        let mut reader = RequestReader::new(segmented(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffed\r\nabc\r\n0\r\n\r\n",
+            1024,
+        ), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(413));
+    }
+
+    #[test]
+    fn test_read_invalid_chunked_request_is_error() {
+        let mut reader = RequestReader::new(segmented(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nabc\r\n0\r\n\r\n",
+            1024,
+        ), timeouts(), limits());
+        assert_eq!(reader.read_request().err().map(|e| e.get_status_code()), Some(400));
+    }
+}
@@ -2,64 +2,130 @@ use crate::http::types::HttpMethod;
 use crate::http::uri;
 use crate::server::context::RequestContext;
 use crate::server::error::{ServerError, StdServerError};
-use crate::server::traits::{Request, Response};
+use crate::server::middleware::Chain;
+use crate::server::runtime::{self, BoxFuture};
+use crate::server::traits::{Request, RequestMiddleware, Response};
 
-use log;
-use regex;
 use std::sync::Arc;
 
+/// Route handler function, takes ownership of the request context and returns it with the response set
+pub type RouteFunc<T, R> = Box<
+    dyn Fn(RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> + Send + Sync + 'static,
+>;
+
+/// Async route handler function, returns a boxed future resolving to the request context
+pub type AsyncRouteFunc<T, R> = Box<
+    dyn Fn(RequestContext<T, R>) -> BoxFuture<Result<RequestContext<T, R>, ServerError>>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+pub enum Handler<T: Request, R: Response> {
+    Sync(RouteFunc<T, R>),
+    Async(AsyncRouteFunc<T, R>),
+}
 
 pub struct Route<T: Request, R: Response> {
     pub path: String,
-    pub func: Box<dyn Fn(RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> + Send + Sync + 'static>,
+    pub handler: Handler<T, R>,
     pub methods: Vec<HttpMethod>,
+    /// Largest request body accepted on this route, `None` to use the server limit
+    pub max_body_size: Option<usize>,
+    /// Runs around the handler, after the application middleware
+    pub middleware: Vec<Arc<dyn RequestMiddleware<T, R>>>,
     regex_path: Option<regex::Regex>,
 }
 
 impl<T: Request, R: Response> Route<T, R> {
     pub fn new(
         path: String,
-        func: Box<dyn Fn(RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> + Send + Sync + 'static>,
+        func: RouteFunc<T, R>,
         methods: Vec<HttpMethod>,
     ) -> Self {
-        match convert_path_to_regex(&path) {
-            Some(val) => {
-                log::debug!(
-                    "Route for path: {} generated a regex match pattern: {}.",
-                    &path,
-                    &val
-                );
-                let re = match regex::Regex::new(&val) {
-                    Ok(val) => val,
-                    Err(_) => panic!(
-                        "Failed to convert the route path for regex matching: {}",
-                        &path
-                    ),
-                };
-                return Self {
-                    path: path,
-                    func: func,
-                    methods: methods,
-                    regex_path: Some(re),
-                };
-            }
-            None => {
-                log::debug!(
-                    "Route for path: {} did not generate a regex match pattern.",
-                    &path
-                );
-                return Self {
-                    path: path,
-                    func: func,
-                    methods: methods,
-                    regex_path: None,
-                };
-            }
+        Self::with_handler(path, Handler::Sync(func), methods)
+    }
+
+    /// Route with an async handler
+    pub fn new_async(
+        path: String,
+        func: AsyncRouteFunc<T, R>,
+        methods: Vec<HttpMethod>,
+    ) -> Self {
+        Self::with_handler(path, Handler::Async(func), methods)
+    }
+
+    fn with_handler(path: String, handler: Handler<T, R>, methods: Vec<HttpMethod>) -> Self {
+        Self {
+            regex_path: compile_path(&path),
+            path,
+            handler,
+            methods,
+            max_body_size: None,
+            middleware: Vec::new(),
         }
     }
 
+    /// Accept request bodies up to `size` bytes on this route instead of the server limit
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = Some(size);
+        self
+    }
+
+    /// Add middleware that only runs for requests dispatched to this route.
+    /// It runs in the order added, inside the application and group middleware.
+    pub fn with_middleware<M: RequestMiddleware<T, R> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
     pub fn get_path_regex(&self) -> &Option<regex::Regex> {
-        return &self.regex_path;
+        &self.regex_path
+    }
+
+    /// Move the route under a group, its path is prefixed and the group middleware runs before its own
+    fn into_group(mut self, prefix: &str, middleware: &[Arc<dyn RequestMiddleware<T, R>>]) -> Self {
+        let path = format!("{}{}", prefix.trim_end_matches('/'), self.path);
+        self.regex_path = compile_path(&path);
+        self.path = path;
+        self.middleware.splice(0..0, middleware.iter().cloned());
+        self
+    }
+}
+
+/// Routes sharing a path prefix and middleware
+pub struct RouteGroup<T: Request, R: Response> {
+    prefix: String,
+    routes: Vec<Route<T, R>>,
+    middleware: Vec<Arc<dyn RequestMiddleware<T, R>>>,
+}
+
+impl<T: Request, R: Response> RouteGroup<T, R> {
+    /// Group routes under `prefix`, the route paths are relative to it
+    pub fn new(prefix: String, routes: Vec<Route<T, R>>) -> Self {
+        Self {
+            prefix,
+            routes,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Add middleware that runs for every route in the group, before the routes' own middleware
+    pub fn with_middleware<M: RequestMiddleware<T, R> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    fn into_routes(self) -> Vec<Route<T, R>> {
+        let RouteGroup {
+            prefix,
+            routes,
+            middleware,
+        } = self;
+        routes
+            .into_iter()
+            .map(|route| route.into_group(&prefix, &middleware))
+            .collect()
     }
 }
 
@@ -69,66 +135,192 @@ pub struct Router<T: Request, R: Response> {
 
 impl<T: Request, R: Response> Router<T, R> {
     pub fn new(routes: Vec<Route<T, R>>) -> Self {
-        Self { routes: routes }
+        Self { routes }
+    }
+
+    /// Add the routes of a group
+    pub fn with_group(mut self, group: RouteGroup<T, R>) -> Self {
+        self.routes.extend(group.into_routes());
+        self
     }
 }
 
 impl<T: Request, R: Response> Router<T, R> {
+    // Find the routes registered for a request path
+    fn match_path(&self, path: &str) -> Vec<&Route<T, R>> {
+        self.routes
+            .iter()
+            .filter(|route| match route.get_path_regex() {
+                Some(val) => val.find(path).is_some(),
+                None => route.path == path,
+            })
+            .collect()
+    }
+
+    /// Methods a path can be requested with, HEAD is allowed wherever GET is and OPTIONS everywhere.
+    /// The path `*` lists the methods of every route.
+    pub fn allowed_methods(&self, path: &str) -> Vec<HttpMethod> {
+        let routes = match path {
+            "*" => self.routes.iter().collect(),
+            _ => self.match_path(path),
+        };
+        let mut methods = Vec::<HttpMethod>::new();
+        for method in routes.iter().flat_map(|route| route.methods.iter()) {
+            if !methods.contains(method) {
+                methods.push(method.clone());
+            }
+        }
+        if methods.contains(&HttpMethod::Get) && !methods.contains(&HttpMethod::Head) {
+            methods.push(HttpMethod::Head);
+        }
+        if !methods.is_empty() && !methods.contains(&HttpMethod::Options) {
+            methods.push(HttpMethod::Options);
+        }
+        methods
+    }
+
+    /// Body size limit of the route a request would be dispatched to, `None` if it has no override
+    pub fn body_limit(&self, method: &HttpMethod, path: &str) -> Option<usize> {
+        let routes = self.match_path(path);
+        let find = |method: &HttpMethod| routes.iter().find(|route| route.methods.contains(method));
+        let route = match find(method) {
+            None if *method == HttpMethod::Head => find(&HttpMethod::Get),
+            val => val,
+        };
+        route.and_then(|route| route.max_body_size)
+    }
+
     // Find the route requested via path matching
-    fn match_path_to_route(&self, request: &impl Request) -> Result<&Route<T, R>, ServerError> {
-        for route in self.routes.iter() {
-            for method in route.methods.iter() {
-                if *method == request.get_method() {
-                    match route.get_path_regex() {
-                        Some(val) => match val.find(&request.get_path()) {
-                            Some(_) => return Ok(&route),
-                            None => {}
-                        },
-                        None => {
-                            if route.path == request.get_path() {
-                                return Ok(&route);
-                            }
-                        },
-                    }
-                }
+    fn match_path_to_route(&self, request: &impl Request) -> Result<Option<&Route<T, R>>, ServerError> {
+        let path = request.get_path();
+        let routes = self.match_path(&path);
+        if routes.is_empty() && path != "*" {
+            return Err(StdServerError::NotFound.to_error());
+        }
+
+        let method = request.get_method();
+        let find = |method: &HttpMethod| {
+            routes
+                .iter()
+                .find(|route| route.methods.contains(method))
+                .copied()
+        };
+
+        match find(&method) {
+            Some(route) => Ok(Some(route)),
+            // HEAD is answered by the GET handler, the body is dropped when the response is written
+            None if method == HttpMethod::Head && find(&HttpMethod::Get).is_some() => {
+                Ok(find(&HttpMethod::Get))
             }
+            // OPTIONS is answered by the router
+            None if method == HttpMethod::Options => Ok(None),
+            None => Err(StdServerError::MethodNotAllowed
+                .to_error()
+                .with_header("Allow", &allow_header(&self.allowed_methods(&path)))),
         }
-        Err(StdServerError::NotFound.to_error())
     }
 
-    // Execute route handler
+    /// Execute route handler, async handlers are run to completion on the calling thread.
+    /// Only for threads outside an async runtime, such as the blocking server's workers,
+    /// async code must use `dispatch_async`. Called from within a runtime it fails with a 500.
     pub fn dispatch(&self, ctx: RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> {
+        // Blocking on a runtime thread would panic
+        if tokio::runtime::Handle::try_current().is_ok() {
+            log::error!("Router::dispatch called from within an async runtime, use dispatch_async instead.");
+            return Err(StdServerError::InternalServerError.to_error());
+        }
+        runtime::block_on(self.dispatch_async(ctx))
+    }
+
+    // Execute route handler from an async task, sync handlers are run as blocking code
+    pub async fn dispatch_async(
+        &self,
+        ctx: RequestContext<T, R>,
+    ) -> Result<RequestContext<T, R>, ServerError> {
+        let mut chain = Chain::default();
+        let result = self.dispatch_chained(ctx, &mut chain).await;
+        runtime::run_blocking(|| chain.leave(result))
+    }
+
+    /// Dispatch a request inside middleware that has already run, the route middleware is added to the chain.
+    /// The caller passes the result back out through the chain.
+    pub(crate) async fn dispatch_chained(
+        &self,
+        ctx: RequestContext<T, R>,
+        chain: &mut Chain<T, R>,
+    ) -> Result<RequestContext<T, R>, ServerError> {
         let mut ctx = ctx;
         let request = ctx.get_request();
 
-        let route = match self.match_path_to_route(request) {
-            Ok(val) => val,
-            Err(e) => return Err(e),
+        let route = match self.match_path_to_route(request)? {
+            Some(val) => val,
+            None => {
+                let mut response = R::new();
+                response.set_status_code(204);
+                response.set_header("Allow", &allow_header(&self.allowed_methods(&request.get_path())));
+                ctx.set_response(response);
+                return Ok(ctx);
+            }
         };
 
-        match route.get_path_regex() {
-            Some(re) => {
-                match extract_path_params(&request.get_path(), re) {
-                    Ok(val) => {
-                        // There should be a better way to do this -> pass request and response around
-                        let mut request = ctx.get_request().clone();
-                        for (name, value) in val.iter() {
-                            // log::debug!("Setting path parameter: {} {}", name, value);
-                            request.set_path_param(name, value);
-                            // log::debug!("Getting path parameter: str {}", request.get_path_param("str").unwrap())
-                        }
-                        ctx.set_request(request);
-                    }
-                    Err(e) => return Err(e),
-                }
+        if let Some(re) = route.get_path_regex() {
+            let params = extract_path_params(&request.get_path(), re)?;
+            // There should be a better way to do this -> pass request and response around
+            let mut request = ctx.get_request().clone();
+            for (name, value) in params.iter() {
+                request.set_path_param(name, value);
+            }
+            ctx.set_request(request);
+        }
+
+        // Execute route middleware pre request
+        ctx = runtime::run_blocking(|| chain.enter(&route.middleware, ctx))?;
+        if ctx.is_finished() {
+            return Ok(ctx);
+        }
+
+        match &route.handler {
+            Handler::Sync(f) => runtime::run_blocking(|| f(ctx)),
+            Handler::Async(f) => f(ctx).await,
+        }
+    }
+}
+
+/// Compile the regex used to match a route path with `{param}` components, `None` for a plain path
+fn compile_path(path: &str) -> Option<regex::Regex> {
+    match convert_path_to_regex(path) {
+        Some(val) => {
+            log::debug!(
+                "Route for path: {} generated a regex match pattern: {}.",
+                path,
+                &val
+            );
+            match regex::Regex::new(&val) {
+                Ok(val) => Some(val),
+                Err(_) => panic!(
+                    "Failed to convert the route path for regex matching: {}",
+                    path
+                ),
             }
-            None => {}
         }
-        let f = &route.func;
-        f(ctx)
+        None => {
+            log::debug!(
+                "Route for path: {} did not generate a regex match pattern.",
+                path
+            );
+            None
+        }
     }
 }
 
+fn allow_header(methods: &[HttpMethod]) -> String {
+    methods
+        .iter()
+        .map(|i| i.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
 fn extract_path_params(
     path: &str,
     path_regex: &regex::Regex,
@@ -153,7 +345,7 @@ fn extract_path_params(
     Ok(path_params)
 }
 
-/// Function
+/// Convert a route path with `{param}` components into a regex with named capture groups
 fn convert_path_to_regex(path: &str) -> Option<String> {
     // regex to extract params from request path - p-char: unreserved / pct-encoded / sub-delims / ":" / "@"
     let path_re = format!(
@@ -174,12 +366,12 @@ fn convert_path_to_regex(path: &str) -> Option<String> {
     let path_components = path.split('/');
     let mut new_components = vec![String::from("^")];
 
-    for component in path_components.filter(|i| *i != "") {
+    for component in path_components.filter(|i| !i.is_empty()) {
         // println!("Comp: {component}");
         new_components.push(String::from("/"));
         match param_re.find(component) {
             Some(_) => {
-                let param_name = String::from(component.trim_matches(&['{', '}']));
+                let param_name = String::from(component.trim_matches(['{', '}']));
                 new_components.push(format!("(?P<{}>{})", param_name, path_re));
             }
             None => new_components.push(String::from(component)),
@@ -282,3 +474,190 @@ fn convert_path_to_regex(path: &str) -> Option<String> {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::application::tests::{recorder, RequireAuth};
+    use crate::server::context::{HttpRequest, HttpResponse};
+    use crate::server::traits::Error;
+    use std::sync::Mutex;
+
+    fn respond(body: &'static str) -> RouteFunc<HttpRequest, HttpResponse> {
+        Box::new(move |mut ctx: RequestContext<HttpRequest, HttpResponse>| {
+            let mut response = HttpResponse::new();
+            response.set_status_code(200);
+            response.set_body(body);
+            ctx.set_response(response);
+            Ok(ctx)
+        })
+    }
+
+    fn router() -> Router<HttpRequest, HttpResponse> {
+        Router::new(vec![
+            Route::new(String::from("/items/{id}"), respond("get"), vec![HttpMethod::Get]),
+            Route::new(String::from("/items/{id}"), respond("put"), vec![HttpMethod::Put]),
+            Route::new(String::from("/items/{id}"), respond("delete"), vec![HttpMethod::Delete]),
+            Route::new(String::from("/upload"), respond("post"), vec![HttpMethod::Post]),
+        ])
+    }
+
+    fn dispatch(method: HttpMethod, path: &str) -> Result<HttpResponse, ServerError> {
+        dispatch_to(&router(), method, path, None)
+    }
+
+    fn dispatch_to(
+        router: &Router<HttpRequest, HttpResponse>,
+        method: HttpMethod,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> Result<HttpResponse, ServerError> {
+        let mut request = HttpRequest::new();
+        request.set_method(method);
+        request.set_path(String::from(path));
+        if let Some(val) = authorization {
+            request.set_header("Authorization", val);
+        }
+        let mut ctx = RequestContext::<HttpRequest, HttpResponse>::new();
+        ctx.set_request(request);
+        router.dispatch(ctx).map(|ctx| Response::clone(ctx.get_response()))
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_within_runtime_is_error() {
+        // Blocking on the test's runtime would panic, dispatch_async must be used instead
+        let result = dispatch(HttpMethod::Get, "/");
+        assert_eq!(result.err().map(|e| e.get_status_code()), Some(500));
+    }
+
+    #[test]
+    fn test_dispatch_by_method() {
+        for (method, body) in [
+            (HttpMethod::Get, "get"),
+            (HttpMethod::Put, "put"),
+            (HttpMethod::Delete, "delete"),
+        ] {
+            let response = dispatch(method, "/items/1").ok().unwrap();
+            assert_eq!(response.get_body().text(), Some(String::from(body)));
+        }
+    }
+
+    #[test]
+    fn test_head_uses_get_handler() {
+        let response = dispatch(HttpMethod::Head, "/items/1").ok().unwrap();
+        assert_eq!(response.get_body().text(), Some(String::from("get")));
+
+        let error = dispatch(HttpMethod::Head, "/upload").err().unwrap();
+        assert_eq!(error.get_status_code(), 405);
+    }
+
+    #[test]
+    fn test_options_lists_allowed_methods() {
+        let response = dispatch(HttpMethod::Options, "/items/1").ok().unwrap();
+        assert_eq!(response.get_status_code(), Some(204));
+        assert_eq!(
+            response.get_header("Allow"),
+            Some(String::from("GET, PUT, DELETE, HEAD, OPTIONS"))
+        );
+
+        let response = dispatch(HttpMethod::Options, "*").ok().unwrap();
+        assert_eq!(
+            response.get_header("Allow"),
+            Some(String::from("GET, PUT, DELETE, POST, HEAD, OPTIONS"))
+        );
+
+        let error = dispatch(HttpMethod::Options, "/missing").err().unwrap();
+        assert_eq!(error.get_status_code(), 404);
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let error = dispatch(HttpMethod::Patch, "/items/1").err().unwrap();
+        assert_eq!(error.get_status_code(), 405);
+        assert_eq!(
+            error.get_headers(),
+            vec![(String::from("Allow"), String::from("GET, PUT, DELETE, HEAD, OPTIONS"))]
+        );
+
+        let error = dispatch(HttpMethod::Get, "/missing").err().unwrap();
+        assert_eq!(error.get_status_code(), 404);
+    }
+
+    #[test]
+    fn test_body_limit_override() {
+        let router = Router::new(vec![
+            Route::new(String::from("/upload/{name}"), respond("get"), vec![HttpMethod::Get]),
+            Route::new(String::from("/upload/{name}"), respond("post"), vec![HttpMethod::Post])
+                .with_max_body_size(1024),
+        ]);
+        assert_eq!(router.body_limit(&HttpMethod::Post, "/upload/a"), Some(1024));
+        assert_eq!(router.body_limit(&HttpMethod::Get, "/upload/a"), None);
+        assert_eq!(router.body_limit(&HttpMethod::Post, "/other"), None);
+    }
+
+    /// Answers every request without reaching the handler
+    struct Maintenance;
+
+    impl RequestMiddleware<HttpRequest, HttpResponse> for Maintenance {
+        fn on_request(
+            &self,
+            mut ctx: RequestContext<HttpRequest, HttpResponse>,
+        ) -> Result<RequestContext<HttpRequest, HttpResponse>, ServerError> {
+            let mut response = HttpResponse::new();
+            response.set_status_code(503);
+            ctx.finish(response);
+            Ok(ctx)
+        }
+    }
+
+    #[test]
+    fn test_route_middleware_finishes_request() {
+        let router = Router::new(vec![
+            Route::new(String::from("/items/{id}"), respond("get"), vec![HttpMethod::Get]).with_middleware(Maintenance),
+        ]);
+        let response = dispatch_to(&router, HttpMethod::Get, "/items/1", None).ok().unwrap();
+        assert_eq!(response.get_status_code(), Some(503));
+        assert_eq!(response.get_body().text(), Some(String::new()));
+    }
+
+    #[test]
+    fn test_group_and_route_middleware() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let admin = RouteGroup::new(
+            String::from("/admin/"),
+            vec![
+                Route::new(String::from("/users/{id}"), respond("user"), vec![HttpMethod::Get])
+                    .with_middleware(recorder("route", &log)),
+                Route::new(String::from("/stats"), respond("stats"), vec![HttpMethod::Get]),
+            ],
+        )
+        .with_middleware(RequireAuth)
+        .with_middleware(recorder("group", &log));
+        let router = Router::new(vec![Route::new(
+            String::from("/public"),
+            respond("public"),
+            vec![HttpMethod::Get],
+        )])
+        .with_group(admin);
+
+        let response = dispatch_to(&router, HttpMethod::Get, "/admin/users/1", Some("token")).ok().unwrap();
+        assert_eq!(response.get_body().text(), Some(String::from("user")));
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["group request", "route request", "route response", "group response"]
+        );
+
+        log.lock().unwrap().clear();
+        let error = dispatch_to(&router, HttpMethod::Get, "/admin/stats", None).err().unwrap();
+        assert_eq!(error.get_status_code(), 401);
+        assert!(log.lock().unwrap().is_empty());
+
+        let response = dispatch_to(&router, HttpMethod::Get, "/public", None).ok().unwrap();
+        assert_eq!(response.get_body().text(), Some(String::from("public")));
+        assert!(log.lock().unwrap().is_empty());
+
+        // Route paths are relative to the group prefix
+        let error = dispatch_to(&router, HttpMethod::Get, "/stats", Some("token")).err().unwrap();
+        assert_eq!(error.get_status_code(), 404);
+    }
+}
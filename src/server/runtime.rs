@@ -0,0 +1,32 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use tokio::runtime::{Builder, Runtime};
+
+/// Boxed future returned by async handlers and middleware
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+thread_local! {
+    // Each blocking worker thread drives async handlers on its own single threaded runtime
+    static RUNTIME: Runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build the async runtime for the worker thread");
+}
+
+/// Run a future to completion on the calling thread.
+/// Used by the blocking server to run async handlers, must not be called from within a runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.with(|runtime| runtime.block_on(future))
+}
+
+/// Run blocking code from an async task.
+/// On a multi threaded runtime other tasks are moved off the worker thread while it runs.
+pub fn run_blocking<F: FnOnce() -> O, O>(f: F) -> O {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
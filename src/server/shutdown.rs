@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::server::listener::Stream;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Handle used to stop a running server.
+/// Clones share the same state, so a handle taken before serving can stop the server from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    sender: watch::Sender<bool>,
+    // Run once when shutdown is requested, used to wake blocking acceptors
+    callbacks: Mutex<Option<Vec<Callback>>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            state: Arc::new(ShutdownState {
+                sender,
+                callbacks: Mutex::new(Some(Vec::new())),
+            }),
+        }
+    }
+
+    /// Stop accepting connections and let in-flight requests finish
+    pub fn shutdown(&self) {
+        self.state.sender.send_replace(true);
+        let callbacks = match self.state.callbacks.lock() {
+            Ok(mut val) => val.take(),
+            Err(_) => None,
+        };
+        for callback in callbacks.into_iter().flatten() {
+            callback();
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.state.sender.borrow()
+    }
+
+    /// Wait until shutdown is requested
+    pub async fn wait(&self) {
+        let mut receiver = self.state.sender.subscribe();
+        // The sender lives as long as this handle, so waiting can't fail
+        let _ = receiver.wait_for(|i| *i).await;
+    }
+
+    /// Shut down when the process receives SIGINT or SIGTERM
+    pub fn shutdown_on_signals(&self) {
+        let handle = self.clone();
+        std::thread::spawn(move || {
+            crate::server::runtime::block_on(wait_for_signal());
+            log::info!("Received shutdown signal.");
+            handle.shutdown();
+        });
+    }
+
+    /// Run a callback when shutdown is requested, straight away if it already has been
+    pub(crate) fn on_shutdown<F: FnOnce() + Send + 'static>(&self, callback: F) {
+        if let Ok(mut callbacks) = self.state.callbacks.lock() {
+            if let Some(val) = callbacks.as_mut() {
+                val.push(Box::new(callback));
+                return;
+            }
+        }
+        callback();
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(val) => val,
+        Err(e) => {
+            log::error!("Failed to listen for SIGTERM: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Open connections of the blocking server, so idle ones can be closed on shutdown
+/// or when connections are waiting for a worker. Connections waiting in the worker pool's
+/// queue are counted too, shutdown waits for them as well.
+#[derive(Default)]
+pub(crate) struct Connections {
+    state: Mutex<ConnectionState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    next_id: usize,
+    closing: bool,
+    /// The shutdown deadline has passed, connections registered from now on are closed straight away
+    expired: bool,
+    /// Connections queued for a worker that haven't been registered yet
+    queued: usize,
+    /// Threads serving connections, each open connection holds one
+    workers: usize,
+    open: HashMap<usize, Tracked>,
+}
+
+impl ConnectionState {
+    /// Queued connections no worker is free for, counting workers whose connection is being closed
+    fn waiting(&self) -> usize {
+        let freeing = self.open.values().filter(|i| i.yielded).count();
+        let free = self.workers.saturating_sub(self.open.len()) + freeing;
+        self.queued.saturating_sub(free)
+    }
+
+    /// Close idle keep-alive connections so their workers can take the waiting ones
+    fn yield_idle(&mut self) {
+        let waiting = self.waiting();
+        for tracked in self.open.values_mut().filter(|i| i.idle && !i.yielded).take(waiting) {
+            log::debug!("Closing an idle connection, another connection is waiting for its worker.");
+            let _ = tracked.stream.shutdown(Shutdown::Read);
+            tracked.yielded = true;
+        }
+    }
+}
+
+struct Tracked {
+    stream: Stream,
+    idle: bool,
+    /// Closed so its worker can serve a waiting connection
+    yielded: bool,
+}
+
+impl Connections {
+    /// Number of worker threads taking connections from the queue
+    pub(crate) fn set_workers(&self, workers: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.workers = workers;
+        }
+    }
+
+    /// Count a connection handed to the worker pool, it stays counted until it is registered.
+    /// An idle connection is closed if every worker is busy.
+    pub(crate) fn queue(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.queued += 1;
+            state.yield_idle();
+        }
+    }
+
+    /// Whether connections are waiting for a worker, connections should close after their response
+    pub(crate) fn is_contended(&self) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.waiting() > 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Stop counting a queued connection that won't be served, such as one the pool turned away
+    pub(crate) fn unqueue(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.queued = state.queued.saturating_sub(1);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Track a connection until the returned guard is dropped, taking it off the queued count
+    pub(crate) fn register(&self, stream: &Stream) -> Option<ConnectionGuard<'_>> {
+        let tracked = stream.try_clone();
+        let mut state = self.state.lock().ok()?;
+        state.queued = state.queued.saturating_sub(1);
+        if state.expired {
+            log::debug!("Closing a connection that waited for a worker past the shutdown deadline.");
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let stream = match tracked {
+            Ok(val) => val,
+            Err(e) => {
+                log::error!("Failed to track the connection: {:?}", e);
+                drop(state);
+                self.changed.notify_all();
+                return None;
+            }
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(
+            id,
+            Tracked {
+                stream,
+                idle: false,
+                yielded: false,
+            },
+        );
+        Some(ConnectionGuard {
+            connections: self,
+            id,
+        })
+    }
+
+    /// Stop reading from idle connections, now and whenever one becomes idle from here on.
+    /// Requests already received are still read and answered.
+    pub(crate) fn close_idle(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closing = true;
+            for tracked in state.open.values().filter(|i| i.idle) {
+                let _ = tracked.stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// Wait for every connection to finish, including queued ones, closing any still open at the deadline.
+    /// Queued connections a worker takes after the deadline are closed without being served.
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut state = match self.state.lock() {
+            Ok(val) => val,
+            Err(_) => return,
+        };
+        while !state.open.is_empty() || state.queued > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                log::warn!(
+                    "Closing {} connections still open at the shutdown deadline.",
+                    state.open.len() + state.queued
+                );
+                state.expired = true;
+                for tracked in state.open.values() {
+                    let _ = tracked.stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            state = match self.changed.wait_timeout(state, remaining) {
+                Ok((val, _)) => val,
+                Err(_) => return,
+            };
+        }
+    }
+
+    fn set_idle(&self, id: usize, idle: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            let closing = state.closing;
+            if let Some(tracked) = state.open.get_mut(&id) {
+                tracked.idle = idle;
+                if idle && closing {
+                    let _ = tracked.stream.shutdown(Shutdown::Read);
+                }
+            }
+            if idle {
+                state.yield_idle();
+            }
+        }
+    }
+
+    fn remove(&self, id: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.open.remove(&id);
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Registration of an open connection, removed when dropped
+pub(crate) struct ConnectionGuard<'a> {
+    connections: &'a Connections,
+    id: usize,
+}
+
+impl ConnectionGuard<'_> {
+    /// Mark the connection as waiting for its next request, or as serving one
+    pub(crate) fn set_idle(&self, idle: bool) {
+        self.connections.set_idle(self.id, idle);
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.connections.remove(self.id);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::server::reader::ReadTimeout;
+
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConnection, StreamOwned};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Certificates and protocol settings for serving HTTPS
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM certificate chain used when no SNI certificate matches the requested name
+    pub cert_path: PathBuf,
+    /// PEM private key of the default certificate
+    pub key_path: PathBuf,
+    /// Certificates selected by the server name the client asks for
+    pub sni_certificates: Vec<SniCertificate>,
+    /// Protocols advertised with ALPN, in order of preference
+    pub alpn_protocols: Vec<String>,
+}
+
+/// Certificate served to clients asking for `server_name`, which may start with a `*.` wildcard
+#[derive(Clone, Debug)]
+pub struct SniCertificate {
+    pub server_name: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new<P: Into<PathBuf>>(cert_path: P, key_path: P) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            sni_certificates: Vec::new(),
+            alpn_protocols: vec![String::from("http/1.1")],
+        }
+    }
+
+    /// Serve a different certificate to clients asking for `server_name`
+    pub fn with_sni_certificate<P: Into<PathBuf>>(mut self, server_name: &str, cert_path: P, key_path: P) -> Self {
+        self.sni_certificates.push(SniCertificate {
+            server_name: server_name.to_string(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    /// Replace the protocols advertised with ALPN
+    pub fn with_alpn_protocols(mut self, protocols: Vec<String>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+}
+
+/// Picks the certificate for the server name sent with SNI, the default one when nothing matches
+#[derive(Debug)]
+struct CertificateResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    fn find(&self, server_name: &str) -> Option<&Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        if let Some(val) = self.by_name.get(&server_name) {
+            return Some(val);
+        }
+        // A wildcard covers a single label
+        let (_, parent) = server_name.split_once('.')?;
+        self.by_name.get(&format!("*.{}", parent))
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificate = match client_hello.server_name() {
+            Some(val) => self.find(val).unwrap_or(&self.default),
+            None => &self.default,
+        };
+        Some(Arc::clone(certificate))
+    }
+}
+
+/// Load the certificates and build the rustls configuration shared by every connection
+pub(crate) fn server_config(config: &TlsConfig) -> io::Result<Arc<rustls::ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut by_name = HashMap::new();
+    for sni in config.sni_certificates.iter() {
+        let certificate = load_certified_key(&provider, &sni.cert_path, &sni.key_path)?;
+        by_name.insert(sni.server_name.to_ascii_lowercase(), certificate);
+    }
+    let resolver = CertificateResolver {
+        default: load_certified_key(&provider, &config.cert_path, &config.key_path)?,
+        by_name,
+    };
+
+    let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|i| i.as_bytes().to_vec())
+        .collect();
+    Ok(Arc::new(server_config))
+}
+
+fn load_certified_key(provider: &CryptoProvider, cert_path: &Path, key_path: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let signing_key = provider.key_provider.load_private_key(key).map_err(invalid_data)?;
+    let certificate = CertifiedKey::new(certs, signing_key);
+    // Catch a key that doesn't belong to the certificate now rather than on the first handshake
+    certificate.keys_match().map_err(invalid_data)?;
+    Ok(Arc::new(certificate))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificates found in {:?}", path),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(val) => Ok(val),
+        None => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no private key found in {:?}", path),
+        )),
+    }
+}
+
+fn invalid_data(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+/// TLS session over a connection of the blocking server
+pub(crate) type TlsStream<S> = StreamOwned<ServerConnection, S>;
+
+impl<S: Read + Write + ReadTimeout> ReadTimeout for TlsStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+/// Complete the handshake on an accepted connection, it has to be done within `timeout`
+pub(crate) fn accept<S: Read + Write + ReadTimeout>(
+    config: &Arc<rustls::ServerConfig>,
+    mut stream: S,
+    timeout: Duration,
+) -> io::Result<TlsStream<S>> {
+    let mut connection = ServerConnection::new(Arc::clone(config)).map_err(invalid_data)?;
+    let mut sock = Handshake {
+        stream: &mut stream,
+        deadline: Instant::now() + timeout,
+    };
+    while connection.is_handshaking() {
+        connection.complete_io(&mut sock)?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// Connection during the handshake, each read only waits for what is left until `deadline`
+/// so a client sending the handshake a few bytes at a time can't hold it open
+struct Handshake<'a, S> {
+    stream: &'a mut S,
+    deadline: Instant,
+}
+
+impl<S: Read + ReadTimeout> Read for Handshake<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(handshake_timed_out());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        match self.stream.read(buf) {
+            // A read timing out reports WouldBlock on unix
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(handshake_timed_out()),
+            result => result,
+        }
+    }
+}
+
+fn handshake_timed_out() -> io::Error {
+    io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")
+}
+
+impl<S: Write> Write for Handshake<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Tell the client no more data is coming before the connection is closed
+pub(crate) fn close<S: Read + Write>(mut stream: TlsStream<S>) {
+    stream.conn.send_close_notify();
+    if let Err(e) = stream.flush() {
+        log::debug!("Failed to send the TLS close notification: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::server::listener::Stream;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Self-signed certificate written to PEM files, with the DER form a client can trust
+    pub(crate) struct TestCertificate {
+        pub(crate) cert_path: PathBuf,
+        pub(crate) key_path: PathBuf,
+        pub(crate) der: CertificateDer<'static>,
+    }
+
+    impl Drop for TestCertificate {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cert_path);
+            let _ = std::fs::remove_file(&self.key_path);
+        }
+    }
+
+    pub(crate) fn self_signed(server_name: &str) -> TestCertificate {
+        let certified = rcgen::generate_simple_self_signed(vec![server_name.to_string()]).unwrap();
+        let prefix = std::env::temp_dir().join(format!(
+            "agire-tls-{}-{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let cert_path = prefix.with_extension("crt");
+        let key_path = prefix.with_extension("key");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        TestCertificate {
+            cert_path,
+            key_path,
+            der: certified.cert.der().clone(),
+        }
+    }
+
+    /// Client session trusting only `certificate`, asking for `server_name` and HTTP/1.1 with ALPN
+    pub(crate) fn client(certificate: &TestCertificate, server_name: &str) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.der.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        ClientConnection::new(Arc::new(config), server_name).unwrap()
+    }
+
+    #[test]
+    fn test_sni_selects_certificate() {
+        let default = self_signed("localhost");
+        let admin = self_signed("admin.test");
+        let wildcard = self_signed("*.api.test");
+        let config = TlsConfig::new(&default.cert_path, &default.key_path)
+            .with_sni_certificate("Admin.Test", &admin.cert_path, &admin.key_path)
+            .with_sni_certificate("*.api.test", &wildcard.cert_path, &wildcard.key_path);
+        let server_config = server_config(&config).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for _ in 0..4 {
+                let stream = Stream::Tcp(listener.accept().unwrap().0);
+                let _ = accept(&server_config, &stream, Duration::from_secs(5)).map(close);
+            }
+        });
+
+        let served = |server_name: &str, trusted: &TestCertificate| {
+            let mut sock = TcpStream::connect(address).unwrap();
+            let mut connection = client(trusted, server_name);
+            let result = connection.complete_io(&mut sock);
+            result.map(|_| connection.peer_certificates().unwrap()[0].clone())
+        };
+        assert_eq!(served("admin.test", &admin).unwrap(), admin.der);
+        assert_eq!(served("v1.api.test", &wildcard).unwrap(), wildcard.der);
+        assert_eq!(served("localhost", &default).unwrap(), default.der);
+        // Names without their own certificate get the default one, which doesn't cover them
+        assert!(served("other.test", &default).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_has_deadline() {
+        let certificate = self_signed("localhost");
+        let config = TlsConfig::new(&certificate.cert_path, &certificate.key_path);
+        let server_config = server_config(&config).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let stream = Stream::Tcp(listener.accept().unwrap().0);
+            let start = Instant::now();
+            let result = accept(&server_config, &stream, Duration::from_millis(200));
+            (result.map(|_| ()), start.elapsed())
+        });
+
+        // Trickle the client hello a byte at a time, each read gets data well within the timeout
+        let mut hello = Vec::new();
+        client(&certificate, "localhost").write_tls(&mut hello).unwrap();
+        let mut sock = TcpStream::connect(address).unwrap();
+        for i in hello.iter() {
+            if sock.write_all(&[*i]).is_err() || server.is_finished() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let (result, elapsed) = server.join().unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_mismatched_key_is_rejected() {
+        let first = self_signed("localhost");
+        let second = self_signed("localhost");
+        let config = TlsConfig::new(&first.cert_path, &second.key_path);
+        let error = server_config(&config).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let config = TlsConfig::new(&first.cert_path, &first.cert_path);
+        assert!(server_config(&config).is_err());
+    }
+}
@@ -1,8 +1,11 @@
-use crate::http::types::HttpMethod;
+use crate::http::body::Body;
+use crate::http::headers::HeaderMap;
+use crate::http::types::{HttpMethod, HttpVersion};
 
 use super::context::RequestContext;
 use super::error::ServerError;
-use std::collections::HashMap;
+use bytes::Bytes;
+use std::net::SocketAddr;
 
 /// Trait that defines methods a request type must have
 /// Request types must implement this for use within route handlers
@@ -18,6 +21,12 @@ pub trait Request: Send + Sync {
     /// Set the requests method
     fn set_method(&mut self, method: HttpMethod);
 
+    /// Get the protocol version the request was sent with
+    fn get_version(&self) -> HttpVersion;
+
+    /// Set the requests protocol version
+    fn set_version(&mut self, version: HttpVersion);
+
     /// Get a path parameter by name
     fn get_path(&self) -> String;
 
@@ -30,20 +39,46 @@ pub trait Request: Send + Sync {
     // Set a path parameter by name
     fn set_path_param(&mut self, name: &str, value: &str);
 
-    /// Get a query parameter by name
+    /// Get a query parameter by name, the first value if the name is repeated
     fn get_query_param(&self, name: &str) -> Option<String>;
 
-    /// Get a request header by name
+    /// Get every value of a query parameter, in the order they were sent
+    fn get_query_params(&self, name: &str) -> Vec<String>;
+
+    /// Add a query parameter, existing values with the same name are kept
+    fn add_query_param(&mut self, name: &str, value: &str);
+
+    /// Get a request header by name, the first value if the header is repeated
     fn get_header(&self, name: &str) -> Option<String>;
 
-    /// Set a header on the request
+    /// Get all request headers
+    fn get_headers(&self) -> &HeaderMap;
+
+    /// Set a header on the request, replacing any existing values
     fn set_header(&mut self, name: &str, value: &str);
 
+    /// Add a header value on the request, existing values are kept
+    fn append_header(&mut self, name: &str, value: &str);
+
+    /// Get a trailer field sent after a chunked body, these are kept apart from the headers
+    fn get_trailer(&self, name: &str) -> Option<String>;
+
+    /// Get all trailer fields
+    fn get_trailers(&self) -> &HeaderMap;
+
+    /// Add a trailer field value, existing values are kept
+    fn append_trailer(&mut self, name: &str, value: &str);
+
     /// Get the requests body
-    fn get_body(&self) -> String;
+    fn get_body(&self) -> Bytes;
 
     /// Set the value of the body
-    fn set_body(&mut self, body: String);
+    fn set_body(&mut self, body: Bytes);
+
+    /// Get the requests body as UTF-8 text, `None` if the body is not valid UTF-8
+    fn get_body_text(&self) -> Option<String> {
+        String::from_utf8(self.get_body().to_vec()).ok()
+    }
 }
 
 /// Trait that defines Response behaviour
@@ -59,39 +94,67 @@ pub trait Response: Send + Sync {
     /// Set the response status code
     fn set_status_code(&mut self, code: usize);
 
-    /// Get a response header
+    /// Get a response header, the first value if the header is repeated
     fn get_header(&self, name: &str) -> Option<String>;
 
     /// Get all headers
-    fn get_headers(&self) -> &HashMap<String, String>;
+    fn get_headers(&self) -> &HeaderMap;
 
-    /// Set a response header
+    /// Set a response header, replacing any existing values
     fn set_header(&mut self, name: &str, value: &str);
 
+    /// Add a response header value, existing values are kept
+    fn append_header(&mut self, name: &str, value: &str);
+
     /// Get the response body
-    fn get_body(&self) -> String;
+    fn get_body(&self) -> &Body;
 
-    /// Set the response body
-    fn set_body(&mut self, body: String);
+    /// Set the response body, either a full body or a stream of chunks
+    fn set_body<B: Into<Body>>(&mut self, body: B);
 }
 
 /// Trait for middleware that operates on bytes from and to the client
-// pub trait RawMiddleware {
+/// Added to an application with `Application::with_raw_middleware`.
+/// Over TLS it sees the decrypted bytes, the ones requests are parsed from and responses written as.
+pub trait RawMiddleware: Send + Sync {
+    /// Called once for every accepted connection, after its TLS handshake when there is one.
+    /// The result sees all of the connection's bytes
+    fn connection(&self, info: &ConnectionInfo) -> Box<dyn RawConnection>;
+}
+
+/// Raw middleware state for a single connection
+pub trait RawConnection: Send {
+    /// Bytes read from the client, before they are parsed into requests.
+    /// Reads don't line up with requests, one may hold part of a request or several.
+    fn on_request(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        bytes
+    }
 
-//     ///
-//     fn on_request(request: [u8]) -> [u8] {
-//         return request;
-//     }
+    /// Bytes about to be written to the client, a response may be written in several parts
+    fn on_response(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        bytes
+    }
+
+    /// The client has nothing more to send, returns the bytes `on_request` held back
+    fn on_eof(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+}
 
-//     ///
-//     fn on_response(response: [u8]) -> [u8] {
-//         return response;
-//     }
-// }
+/// Connection handed to `RawMiddleware::connection`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Unique among the connections accepted by an application
+    pub id: u64,
+    /// Address of the client, `None` over Unix sockets
+    pub peer_addr: Option<SocketAddr>,
+}
 
 /// Trait for middleware that operates on impl Request & impl Response types
+/// Added to an application with `Application::with_middleware`, or to a route or group with `Route::with_middleware` and `RouteGroup::with_middleware`
 pub trait RequestMiddleware<T: Request, R: Response>: Send + Sync {
     // This function takes ownership of Request to mutate as needed
+    // Answer the request early with `RequestContext::finish`
     fn on_request(&self, ctx: RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> {
         Ok(ctx)
     }
@@ -102,9 +165,24 @@ pub trait RequestMiddleware<T: Request, R: Response>: Send + Sync {
     fn on_response(&self, ctx: RequestContext<T, R>) -> Result<RequestContext<T, R>, ServerError> {
         Ok(ctx)
     }
+
+    /// Called with an error from inside this middleware, innermost middleware first,
+    /// before the error is turned into a response that goes through `on_response`.
+    /// Application middleware also sees requests rejected before it could run, such as malformed ones,
+    /// with an empty request of the version the client used.
+    /// Returns the error to send, to log it or rewrite it
+    fn on_error(&self, request: &T, error: ServerError) -> ServerError {
+        let _ = request;
+        error
+    }
 }
 
 pub trait Error {
     fn get_status_code(&self) -> usize;
     fn get_detail(&self) -> String;
+
+    /// Headers to send with the error response
+    fn get_headers(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
 }
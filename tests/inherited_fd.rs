@@ -0,0 +1,78 @@
+//! Starts the server binary the way a supervisor would, with its listening socket already open
+#![cfg(unix)]
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// Descriptor the socket is passed as, the first one used by systemd
+const INHERITED_FD: i32 = 3;
+
+/// Server process, killed when the test is done whether it passed or not
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Run `command` with `listener` open as `INHERITED_FD`
+fn spawn(mut command: Command, listener: &TcpListener) -> Server {
+    let fd = listener.as_raw_fd();
+    // Safety: only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 clears close-on-exec on the copy, a descriptor already in place keeps it
+            let ret = if fd == INHERITED_FD {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, INHERITED_FD)
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    Server(child)
+}
+
+fn get(address: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_serves_fd_passed_with_flag() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_http-server-starter-rust"));
+    command.args(["--listen-fd", &INHERITED_FD.to_string()]);
+    let _server = spawn(command, &listener);
+
+    assert!(get(listener.local_addr().unwrap()).starts_with("HTTP/1.1 200 OK\r\n"));
+}
+
+#[test]
+fn test_serves_fd_passed_by_systemd() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // LISTEN_PID has to be the server's own, the shell keeps its pid when it execs the server
+    let mut command = Command::new("sh");
+    command.args([
+        "-c",
+        "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\"",
+        env!("CARGO_BIN_EXE_http-server-starter-rust"),
+    ]);
+    let _server = spawn(command, &listener);
+
+    assert!(get(listener.local_addr().unwrap()).starts_with("HTTP/1.1 200 OK\r\n"));
+}